@@ -1,13 +1,29 @@
-use crate::util::{append_all, cache_dir, AtsiError, Result, SyncResult, USER_AGENT};
+use crate::util::config::Config;
+use crate::util::{append_all, cache_dir, AtsiError, SyncResult, USER_AGENT};
 
 use std::fs::{self, File};
 use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 
+use super::fs_driver::FsDriver;
+
+use sha2::{Digest, Sha256, Sha512};
 use yaml_rust::{Yaml, YamlLoader};
 
-pub const VERSION: &str = "3.16";
-pub const ARCH: &str = "x86_64";
+pub const VERSION: &str = "latest";
+pub const MIRROR_ENV: &str = "ATSI_ALPINE_MIRROR";
+pub const DEFAULT_MIRRORS: [&str; 2] = [
+    "https://dl-cdn.alpinelinux.org/alpine",
+    "https://cz.alpinelinux.org/alpine",
+];
+
+// Every mirror gets this many tries before moving on to the next one.
+const FETCH_ATTEMPTS: u32 = 3;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+// How long `latest` keeps resolving to the same branch, and `edge` or a
+// branch to the same cached tarball, without asking a mirror again.
+const LATEST_TTL: Duration = Duration::from_secs(24 * 60 * 60);
 
 fn rootfs_base_directory() -> PathBuf {
     let mut path = cache_dir();
@@ -15,88 +31,618 @@ fn rootfs_base_directory() -> PathBuf {
     path
 }
 
-pub fn rootfs_tarball(version: &str) -> PathBuf {
+pub fn rootfs_tarball(version: &str, arch: &str) -> PathBuf {
     let mut path = rootfs_base_directory();
-    path.push(format!("alpine-rootfs-{}-{}.tar.gz", version, ARCH));
+    path.push(format!("alpine-rootfs-{}-{}.tar.gz", version, arch));
     path
 }
 
-pub fn rootfs_path(version: &str) -> PathBuf {
+pub fn rootfs_path(version: &str, arch: &str) -> PathBuf {
     let mut path = rootfs_base_directory();
-    path.push(format!("alpine-rootfs-{}-{}", version, ARCH));
+    path.push(format!("alpine-rootfs-{}-{}", version, arch));
     path
 }
 
-pub async fn download_rootfs(version: &str) -> SyncResult<()> {
-    if Path::new(&rootfs_tarball(version)).exists() {
-        return Ok(());
+/// The host-side apk package cache that's shared by every container and
+/// layer build using this version, mounted at `/etc/apk/cache`.
+pub fn apk_cache(version: &str, arch: &str) -> PathBuf {
+    append_all(&cache_dir(), vec!["apk", &format!("{}-{}", version, arch)])
+}
+
+/// Deletes cached apk files that were downloaded more than `max_age` ago,
+/// or all of them without a `max_age`. Returns how many files and bytes
+/// were freed.
+pub fn prune_apk_cache(max_age: Option<Duration>) -> SyncResult<(u64, u64)> {
+    let caches = match fs::read_dir(append_all(&cache_dir(), vec!["apk"])) {
+        Ok(caches) => caches,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok((0, 0)),
+        Err(err) => return Err(Box::new(err)),
+    };
+    let (mut files, mut bytes) = (0, 0);
+    for cache in caches {
+        for entry in fs::read_dir(cache?.path())? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if !metadata.is_file() {
+                continue;
+            }
+            let expired = match max_age {
+                Some(max_age) => metadata.modified()?.elapsed().unwrap_or_default() > max_age,
+                None => true,
+            };
+            if expired {
+                fs::remove_file(entry.path())?;
+                files += 1;
+                bytes += metadata.len();
+            }
+        }
     }
-    info!("downloading Alpine rootfs v{}...", version);
-    let manifest_url = format!("{}/latest-releases.yaml", base_url(version, ARCH));
-    let manifest_text = reqwest::Client::builder()
-        .user_agent(USER_AGENT)
-        .build()?
-        .get(manifest_url)
-        .send()
-        .await?
-        .text()
-        .await?;
+    Ok((files, bytes))
+}
 
-    let docs = YamlLoader::load_from_str(manifest_text.as_str())?;
-    let manifest = &docs[0];
-    if let Some(vec) = manifest.as_vec() {
-        let maybe_rootfs_manifest = vec.iter().find(|yaml| {
-            matches!(
-                yaml["flavor"].as_str(),
-                Some("minirootfs") | Some("alpine-minirootfs")
+pub fn rootfs_signature(version: &str, arch: &str) -> PathBuf {
+    PathBuf::from(format!("{}.asc", rootfs_tarball(version, arch).display()))
+}
+
+/// Returns the mirrors to download Alpine from, in the order they should be
+/// tried. `--mirror` wins over `$ATSI_ALPINE_MIRROR`, which wins over the
+/// config file, and the official mirrors are used if none are set.
+pub fn mirrors(cli_mirrors: &[String]) -> SyncResult<Vec<String>> {
+    let mirrors = if !cli_mirrors.is_empty() {
+        cli_mirrors.to_vec()
+    } else if let Ok(env_mirrors) = std::env::var(MIRROR_ENV) {
+        env_mirrors.split(',').map(|m| m.to_string()).collect()
+    } else {
+        Config::load()?.mirrors
+    };
+    let mirrors: Vec<String> = mirrors
+        .iter()
+        .map(|m| m.trim().trim_end_matches('/').to_string())
+        .filter(|m| !m.is_empty())
+        .collect();
+    if mirrors.is_empty() {
+        Ok(DEFAULT_MIRRORS.iter().map(|m| m.to_string()).collect())
+    } else {
+        Ok(mirrors)
+    }
+}
+
+/// Downloads the rootfs tarball for `version`, unless it's already cached.
+/// With `verify_signature`, the tarball also has to be signed by one of
+/// Alpine's release keys, including when it was cached without a signature.
+///
+/// Mirrors are tried in order until one of them works, and the mirror that
+/// was used is returned. When `offline`, only the cache is used.
+pub async fn download_rootfs(
+    version: &str,
+    arch: &str,
+    mirrors: &[String],
+    verify_signature: bool,
+    offline: bool,
+) -> SyncResult<String> {
+    if !offline && is_rolling(version) && !is_fresh(&rootfs_tarball(version, arch)) {
+        refresh_rootfs(version, arch, mirrors, verify_signature).await;
+    }
+    let needs_download = || {
+        !rootfs_tarball(version, arch).exists()
+            || (verify_signature && !rootfs_signature(version, arch).exists())
+    };
+    if !needs_download() {
+        return mirrors
+            .first()
+            .cloned()
+            .ok_or_else(|| Box::new(AtsiError::AlpineMirrorsUnavailable).into());
+    }
+    if offline {
+        return Err(Box::new(if rootfs_tarball(version, arch).exists() {
+            AtsiError::OfflineCacheMiss(
+                format!("the signature of the alpine {} rootfs", version),
+                "run without --verify-signature".to_string(),
             )
-        });
-        if let Some(rootfs_manifest) = maybe_rootfs_manifest {
-            debug!("found alpine minirootfs! downloading...");
-            download_rootfs_real(rootfs_manifest, version).await?;
-            Ok(())
         } else {
+            AtsiError::OfflineCacheMiss(
+                format!("the alpine {} rootfs for {}", version, arch),
+                format!(
+                    "import it with `@ rootfs import <tarball> --as {} --arch {}`",
+                    version, arch
+                ),
+            )
+        }));
+    }
+    for mirror in mirrors {
+        match download_from_mirror(mirror, version, arch, verify_signature).await {
+            Ok(()) => return Ok(mirror.clone()),
+            Err(err) => warn!("couldn't download alpine from {}: {}", mirror, err),
+        }
+    }
+    Err(Box::new(AtsiError::AlpineMirrorsUnavailable))
+}
+
+/// Resolves the version given to `--alpine` into the version that's used to
+/// key the rootfs cache: `latest` becomes the newest stable branch, ex.
+/// `3.18`, while `edge`, branches, exact releases like `3.18.4` and names
+/// of imported rootfs are kept as they are.
+pub async fn resolve_version(
+    version: &str,
+    arch: &str,
+    mirrors: &[String],
+    offline: bool,
+) -> SyncResult<String> {
+    if rootfs_tarball(version, arch).exists() {
+        return Ok(version.to_string());
+    }
+    let version = version.trim_start_matches('v');
+    match version {
+        "edge" => Ok(version.to_string()),
+        "latest" | "latest-stable" => resolve_latest(arch, mirrors, offline).await,
+        _ if is_version(version) => Ok(version.to_string()),
+        _ => Err(Box::new(AtsiError::InvalidAlpineVersion(
+            version.to_string(),
+        ))),
+    }
+}
+
+/// Looks up the current stable branch in the `latest-stable` release index.
+/// The answer is cached for a day, and a stale answer is still used when no
+/// mirror can be reached or when `offline`.
+async fn resolve_latest(arch: &str, mirrors: &[String], offline: bool) -> SyncResult<String> {
+    let cache = append_all(&rootfs_base_directory(), vec!["latest-stable"]);
+    let cached = fs::read_to_string(&cache).ok();
+    match (&cached, is_fresh(&cache) || offline) {
+        (Some(cached), true) => return Ok(cached.trim().to_string()),
+        (None, true) => {
+            return Err(Box::new(AtsiError::OfflineCacheMiss(
+                "the latest alpine version".to_string(),
+                "pass an explicit version with --alpine".to_string(),
+            )))
+        }
+        _ => {}
+    }
+
+    for mirror in mirrors {
+        match fetch_latest_branch(mirror, arch).await {
+            Ok(branch) => {
+                debug!("latest stable alpine is {}", branch);
+                fs::create_dir_all(rootfs_base_directory())?;
+                fs::write(&cache, &branch)?;
+                return Ok(branch);
+            }
+            Err(err) => warn!("couldn't resolve latest alpine from {}: {}", mirror, err),
+        }
+    }
+    match cached {
+        Some(cached) => {
+            warn!("using previously resolved latest alpine {}", cached.trim());
+            Ok(cached.trim().to_string())
+        }
+        None => Err(Box::new(AtsiError::AlpineMirrorsUnavailable)),
+    }
+}
+
+async fn fetch_latest_branch(mirror: &str, arch: &str) -> SyncResult<String> {
+    let manifest_url = format!(
+        "{}/latest-stable/releases/{}/latest-releases.yaml",
+        mirror, arch
+    );
+    let manifest_text = String::from_utf8(fetch(&manifest_url).await?)?;
+    let docs = YamlLoader::load_from_str(manifest_text.as_str())?;
+    // Every entry in the manifest is from the same branch, ex. `v3.18`.
+    match docs
+        .first()
+        .and_then(|manifest| manifest[0]["branch"].as_str())
+    {
+        Some(branch) => Ok(branch.trim_start_matches('v').to_string()),
+        None => Err(Box::new(AtsiError::AlpineManifestInvalid)),
+    }
+}
+
+/// The directory on a mirror that `version` is published under, ex. `v3.18`
+/// for both `3.18` and `3.18.4`.
+fn branch(version: &str) -> String {
+    if version == "edge" {
+        return version.to_string();
+    }
+    let parts: Vec<&str> = version.splitn(3, '.').collect();
+    format!("v{}", parts[..parts.len().min(2)].join("."))
+}
+
+/// Whether `version` is an Alpine branch like `3.18` or a release like
+/// `3.18.4`, as opposed to `edge` or the name of an imported rootfs.
+fn is_version(version: &str) -> bool {
+    let parts: Vec<&str> = version.split('.').collect();
+    (parts.len() == 2 || parts.len() == 3)
+        && parts
+            .iter()
+            .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()))
+}
+
+fn is_release(version: &str) -> bool {
+    version.split('.').count() == 3
+}
+
+/// Whether `version` moves on over time, ex. `edge` or a branch like `3.18`,
+/// as opposed to a release or an imported rootfs.
+fn is_rolling(version: &str) -> bool {
+    version == "edge" || (is_version(version) && !is_release(version))
+}
+
+/// Whether `path` was written less than `LATEST_TTL` ago.
+fn is_fresh(path: &Path) -> bool {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .is_some_and(|age| age < LATEST_TTL)
+}
+
+/// A minirootfs tarball on a mirror, and the digests it should have.
+struct RootfsRelease {
+    file: String,
+    sha512: Option<String>,
+    sha256: Option<String>,
+}
+
+impl RootfsRelease {
+    fn from_manifest(rootfs_manifest: &Yaml) -> SyncResult<Self> {
+        match rootfs_manifest["file"].as_str() {
+            Some(file) => Ok(Self {
+                file: file.to_string(),
+                sha512: rootfs_manifest["sha512"].as_str().map(|s| s.to_string()),
+                sha256: rootfs_manifest["sha256"].as_str().map(|s| s.to_string()),
+            }),
+            None => Err(Box::new(AtsiError::AlpineManifestFileMissing)),
+        }
+    }
+}
+
+/// Finds the minirootfs for `version` on a mirror. `latest-releases.yaml`
+/// only lists the newest release of a branch, so older releases are found
+/// by their file name instead, with the digest from the `.sha256` file that
+/// Alpine publishes next to them.
+async fn find_release(base_url: &str, version: &str, arch: &str) -> SyncResult<RootfsRelease> {
+    let manifest_url = format!("{}/latest-releases.yaml", base_url);
+    let manifest_text = String::from_utf8(fetch(&manifest_url).await?)?;
+
+    let docs = YamlLoader::load_from_str(manifest_text.as_str())?;
+    let manifest = &docs[0];
+    let vec = match manifest.as_vec() {
+        Some(vec) => vec,
+        None => return Err(Box::new(AtsiError::AlpineManifestInvalid)),
+    };
+    let maybe_rootfs_manifest = vec.iter().find(|yaml| {
+        matches!(
+            yaml["flavor"].as_str(),
+            Some("minirootfs") | Some("alpine-minirootfs")
+        )
+    });
+    let rootfs_manifest = match maybe_rootfs_manifest {
+        Some(rootfs_manifest) => rootfs_manifest,
+        None => {
             error!(
                 "expected alpine minirootfs in manifest, but manifest was\n{}",
                 manifest_text
             );
-            Err(Box::new(AtsiError::AlpineManifestMissing))
+            return Err(Box::new(AtsiError::AlpineManifestMissing));
         }
+    };
+    if !is_release(version) || rootfs_manifest["version"].as_str() == Some(version) {
+        return RootfsRelease::from_manifest(rootfs_manifest);
+    }
+
+    let file = format!("alpine-minirootfs-{}-{}.tar.gz", version, arch);
+    let checksum_url = format!("{}/{}.sha256", base_url, file);
+    debug!(
+        "{} isn't the latest release, using {}",
+        version, checksum_url
+    );
+    let checksum = String::from_utf8(fetch(&checksum_url).await?)?;
+    // Same format as sha256sum's output: `<digest>  <file>`.
+    match checksum.split_whitespace().next() {
+        Some(sha256) => Ok(RootfsRelease {
+            file,
+            sha512: None,
+            sha256: Some(sha256.to_string()),
+        }),
+        None => Err(Box::new(AtsiError::AlpineManifestChecksumMissing)),
+    }
+}
+
+async fn download_from_mirror(
+    mirror: &str,
+    version: &str,
+    arch: &str,
+    verify_signature: bool,
+) -> SyncResult<()> {
+    // An earlier mirror may have thrown away a cached tarball that didn't
+    // have a valid signature.
+    let cached = rootfs_tarball(version, arch).exists();
+    if cached {
+        info!(
+            "verifying cached Alpine {} rootfs with {}...",
+            version, mirror
+        );
     } else {
-        Err(Box::new(AtsiError::AlpineManifestInvalid))
+        info!(
+            "downloading Alpine {} rootfs ({}) from {}...",
+            version, arch, mirror
+        );
     }
+    let base_url = base_url(mirror, version, arch);
+    let release = find_release(&base_url, version, arch).await?;
+    if !cached {
+        debug!("found alpine minirootfs {}! downloading...", release.file);
+        download_rootfs_real(&base_url, &release, version, arch, verify_signature).await?;
+    } else if let Err(err) = download_signature(
+        &base_url,
+        &release,
+        version,
+        arch,
+        &rootfs_tarball(version, arch),
+    )
+    .await
+    {
+        // Nothing that came out of an untrusted tarball can be used either.
+        fs::remove_file(rootfs_tarball(version, arch))?;
+        FsDriver::new().drop_version(&rootfs_path(version, arch))?;
+        super::layer::drop_all(version, arch)?;
+        return Err(err);
+    }
+    Ok(())
 }
 
-async fn download_rootfs_real(rootfs_manifest: &Yaml, version: &str) -> SyncResult<PathBuf> {
-    match rootfs_manifest["file"].as_str() {
-        Some(rootfs_filename) => {
-            // minirootfs is a ~3MB tarball, so we can afford to hold
-            // it all in memory.
-            let rootfs_url = format!("{}/{}", base_url(version, ARCH), rootfs_filename);
+/// Checks a cached rolling version against the mirrors, and replaces it if
+/// a newer release was published since it was downloaded. The stale rootfs
+/// is kept when no mirror can be reached.
+async fn refresh_rootfs(version: &str, arch: &str, mirrors: &[String], verify_signature: bool) {
+    for mirror in mirrors {
+        match refresh_from_mirror(mirror, version, arch, verify_signature).await {
+            Ok(()) => return,
+            Err(err) => warn!("couldn't check {} for a newer alpine: {}", mirror, err),
+        }
+    }
+    warn!("using the cached alpine {} rootfs", version);
+}
 
-            let download_response = reqwest::get(rootfs_url).await?;
-            let rootfs_bytes = download_response.bytes().await?;
+async fn refresh_from_mirror(
+    mirror: &str,
+    version: &str,
+    arch: &str,
+    verify_signature: bool,
+) -> SyncResult<()> {
+    let base_url = base_url(mirror, version, arch);
+    let release = find_release(&base_url, version, arch).await?;
+    let tarball = rootfs_tarball(version, arch);
+    let (expected, actual) = checksums(&release, &tarball)?;
+    if expected.eq_ignore_ascii_case(&actual) {
+        debug!("cached alpine {} rootfs is up to date", version);
+        File::options()
+            .write(true)
+            .open(&tarball)?
+            .set_modified(SystemTime::now())?;
+        return Ok(());
+    }
+
+    info!(
+        "updating Alpine {} rootfs ({}) from {}...",
+        version, arch, mirror
+    );
+    download_rootfs_real(&base_url, &release, version, arch, verify_signature).await?;
+    // Whatever was verified or extracted before belongs to the old tarball.
+    if !verify_signature {
+        let _ = fs::remove_file(rootfs_signature(version, arch));
+    }
+    FsDriver::new().drop_version(&rootfs_path(version, arch))?;
+    super::layer::drop_all(version, arch)
+}
+
+async fn download_rootfs_real(
+    base_url: &str,
+    release: &RootfsRelease,
+    version: &str,
+    arch: &str,
+    verify_signature: bool,
+) -> SyncResult<PathBuf> {
+    // minirootfs is a ~3MB tarball, so we can afford to hold
+    // it all in memory.
+    let rootfs_url = format!("{}/{}", base_url, release.file);
+    let rootfs_bytes = fetch(&rootfs_url).await?;
 
-            let output_path = rootfs_tarball(version);
-            fs::create_dir_all(&rootfs_base_directory())?;
-            let mut output_file = fs::OpenOptions::new()
-                .write(true)
-                .create_new(true)
-                .open(&output_path)?;
-            output_file.write_all(&rootfs_bytes)?;
-            Ok(output_path)
+    let output_path = rootfs_tarball(version, arch);
+    fs::create_dir_all(rootfs_base_directory())?;
+    // Only verified tarballs are moved into the cache, so that a
+    // corrupted or truncated download doesn't stick around forever.
+    let partial_path = PathBuf::from(format!("{}.part", output_path.display()));
+    let mut output_file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&partial_path)?;
+    output_file.write_all(&rootfs_bytes)?;
+    if let Err(err) = verify_checksum(release, &partial_path) {
+        fs::remove_file(&partial_path)?;
+        return Err(err);
+    }
+    if verify_signature {
+        if let Err(err) = download_signature(base_url, release, version, arch, &partial_path).await
+        {
+            fs::remove_file(&partial_path)?;
+            return Err(err);
         }
-        None => Err(Box::new(AtsiError::AlpineManifestFileMissing)),
     }
+    fs::rename(&partial_path, &output_path)?;
+    Ok(output_path)
 }
 
-pub fn extract_rootfs_to_path(version: &str, target: &Path) -> Result<()> {
-    extract_tarball(&rootfs_tarball(version), target)?;
-    setup_rootfs(target)?;
+/// Downloads the detached signature that Alpine publishes next to the
+/// minirootfs, and checks `tarball` against it. The signature is only kept
+/// around if it's valid.
+async fn download_signature(
+    base_url: &str,
+    release: &RootfsRelease,
+    version: &str,
+    arch: &str,
+    tarball: &Path,
+) -> SyncResult<()> {
+    let signature_url = format!("{}/{}.asc", base_url, release.file);
+    debug!("downloading signature from {}", signature_url);
+    let signature_bytes = fetch(&signature_url).await?;
+
+    let signature_path = rootfs_signature(version, arch);
+    fs::write(&signature_path, &signature_bytes)?;
+    if let Err(err) = super::gpg::verify_alpine_signature(tarball, &signature_path) {
+        fs::remove_file(&signature_path)?;
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// GETs `url`, retrying with exponential backoff when the request fails in
+/// a way that might go away by itself. Client errors like a 404 aren't
+/// retried.
+async fn fetch(url: &str) -> SyncResult<Vec<u8>> {
+    let client = reqwest::Client::builder().user_agent(USER_AGENT).build()?;
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        let result = match client.get(url).send().await {
+            Ok(response) => response.error_for_status(),
+            Err(err) => Err(err),
+        };
+        let err = match result {
+            Ok(response) => return Ok(response.bytes().await?.to_vec()),
+            Err(err) => err,
+        };
+        let retryable = err.status().is_none_or(|status| status.is_server_error());
+        if !retryable || attempt >= FETCH_ATTEMPTS {
+            return Err(Box::new(err));
+        }
+        debug!(
+            "fetching {} failed (attempt {}/{}), retrying in {:?}: {}",
+            url, attempt, FETCH_ATTEMPTS, backoff, err
+        );
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+        attempt += 1;
+    }
+}
+
+/// The apk repositories for `version` on `mirror`, in the format of
+/// `/etc/apk/repositories`. Imported rootfs aren't necessarily an Alpine
+/// release, so they keep the repositories they came with.
+pub fn repositories(mirror: &str, version: &str) -> Option<String> {
+    if version != "edge" && !is_version(version) {
+        return None;
+    }
+    Some(format!(
+        "{0}/{1}/main\n{0}/{1}/community\n",
+        mirror,
+        branch(version)
+    ))
+}
+
+/// Checks a downloaded file against the digest that the release manifest
+/// lists for it, preferring sha512 when both are present.
+fn verify_checksum(release: &RootfsRelease, path: &Path) -> SyncResult<()> {
+    let (expected, actual) = checksums(release, path)?;
+    if !expected.eq_ignore_ascii_case(&actual) {
+        error!(
+            "checksum mismatch for {}: expected {}, got {}",
+            path.display(),
+            expected,
+            actual
+        );
+        return Err(Box::new(AtsiError::AlpineChecksumMismatch));
+    }
+    debug!("verified checksum of {}", path.display());
+    Ok(())
+}
+
+/// The digest that the release manifest lists for a file, and the file's
+/// actual digest.
+fn checksums<'a>(release: &'a RootfsRelease, path: &Path) -> SyncResult<(&'a str, String)> {
+    if let Some(expected) = &release.sha512 {
+        Ok((expected, hash_file::<Sha512>(path)?))
+    } else if let Some(expected) = &release.sha256 {
+        Ok((expected, hash_file::<Sha256>(path)?))
+    } else {
+        Err(Box::new(AtsiError::AlpineManifestChecksumMissing))
+    }
+}
+
+pub fn hash_file<D: Digest + Write>(path: &Path) -> SyncResult<String> {
+    let mut hasher = D::new();
+    std::io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// Extracts the cached rootfs tarball into `rootfs_path(version, arch)`,
+/// once. The extracted rootfs is shared as the read-only lower layer of
+/// every container using this version.
+///
+/// `rootfs_path` points at a version named after the tarball's digest, so
+/// that replacing the tarball never touches a rootfs that's in use.
+pub fn extract_rootfs(version: &str, arch: &str) -> SyncResult<()> {
+    let target = rootfs_path(version, arch);
+    if target.exists() {
+        return Ok(());
+    }
+    debug!("extracting alpine {} rootfs ({})...", version, arch);
+    let tarball = rootfs_tarball(version, arch);
+    let digest: String = hash_file::<Sha256>(&tarball)?.chars().take(16).collect();
+    // Extract to a private directory first and then move it into place, so
+    // that a concurrent `@ run` never sees a half-extracted rootfs.
+    let staging = PathBuf::from(format!("{}.{}", target.display(), std::process::id()));
+    let extracted = extract_tarball(&tarball, &staging).and_then(|_| setup_rootfs(&staging));
+    if let Err(err) = extracted {
+        super::idmap::remove_dir_all(&staging)?;
+        return Err(err);
+    }
+    FsDriver::new().install_version(&staging, &target, &digest)
+}
+
+/// Copies a minirootfs-style tarball into the cache as `name`, so that it
+/// can be used with `--alpine <name>` without any network access. Replaces
+/// any rootfs that was already cached under that name, along with the
+/// layers that were built on top of it.
+pub fn import_rootfs(tarball: &Path, name: &str, arch: &str) -> SyncResult<()> {
+    if name.is_empty() || name.contains('/') || name.starts_with('.') {
+        return Err(Box::new(AtsiError::InvalidRootfsName(name.to_string())));
+    }
+    // Reading the whole archive catches truncated or non-tar files before
+    // they can replace anything in the cache.
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(File::open(tarball)?));
+    for entry in archive.entries()? {
+        entry?;
+    }
+
+    let output_path = rootfs_tarball(name, arch);
+    fs::create_dir_all(rootfs_base_directory())?;
+    let partial_path = PathBuf::from(format!("{}.part", output_path.display()));
+    fs::copy(tarball, &partial_path)?;
+    fs::rename(&partial_path, &output_path)?;
+
+    // Whatever was extracted or verified before belongs to the old tarball.
+    let _ = fs::remove_file(rootfs_signature(name, arch));
+    // Running containers keep using the old rootfs and layers until they
+    // exit; whatever earlier imports left behind for them is cleaned up now.
+    let fs_driver = FsDriver::new();
+    fs_driver.drop_version(&rootfs_path(name, arch))?;
+    fs_driver.prune_all_versions(&rootfs_base_directory())?;
+    super::layer::drop_all(name, arch)?;
+    info!(
+        "imported {} as alpine {} ({})",
+        tarball.display(),
+        name,
+        arch
+    );
     Ok(())
 }
 
-fn extract_tarball(path: &PathBuf, target_path: &Path) -> Result<()> {
+pub fn extract_tarball(path: &Path, target_path: &Path) -> SyncResult<()> {
     let tarball = fs::File::open(path)?;
     let tar = flate2::read::GzDecoder::new(tarball);
     let mut archive = tar::Archive::new(tar);
@@ -104,7 +650,7 @@ fn extract_tarball(path: &PathBuf, target_path: &Path) -> Result<()> {
     Ok(())
 }
 
-fn setup_rootfs(rootfs: &Path) -> Result<()> {
+pub fn setup_rootfs(rootfs: &Path) -> SyncResult<()> {
     File::create(append_all(rootfs, vec!["dev", "null"]))?;
     File::create(append_all(rootfs, vec!["dev", "zero"]))?;
     File::create(append_all(rootfs, vec!["dev", "random"]))?;
@@ -122,9 +668,209 @@ fn setup_rootfs(rootfs: &Path) -> Result<()> {
     Ok(())
 }
 
-fn base_url(version: &str, arch: &str) -> String {
-    format!(
-        "https://cz.alpinelinux.org/alpine/v{}/releases/{}",
-        version, arch
-    )
+fn base_url(mirror: &str, version: &str, arch: &str) -> String {
+    format!("{}/{}/releases/{}", mirror, branch(version), arch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::scratch::ScratchDirectory;
+
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+
+    const SIGNED: &[u8] = include_bytes!("testdata/signed.txt");
+    const SIGNATURE: &[u8] = include_bytes!("testdata/signed.txt.asc");
+    const TAMPERED: &[u8] = include_bytes!("testdata/tampered.txt");
+
+    // The cache's location comes from the environment, which every test in
+    // the process shares.
+    static CACHE_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+    /// Points the cache at a scratch directory until the guard is dropped.
+    async fn scratch_cache() -> (tokio::sync::MutexGuard<'static, ()>, ScratchDirectory) {
+        let lock = CACHE_LOCK.lock().await;
+        let cache = ScratchDirectory::new("alpine");
+        std::env::set_var("XDG_CACHE_HOME", &*cache);
+        (lock, cache)
+    }
+
+    /// Serves `files` by path over HTTP on localhost, and returns its URL.
+    fn serve(files: HashMap<String, Vec<u8>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut reader = BufReader::new(&stream);
+                let mut request = String::new();
+                let _ = reader.read_line(&mut request);
+                let mut header = String::new();
+                while reader.read_line(&mut header).is_ok_and(|n| n > 2) {
+                    header.clear();
+                }
+                let path = request.split_whitespace().nth(1).unwrap_or_default();
+                let (status, body) = match files.get(path) {
+                    Some(body) => ("200 OK", body.as_slice()),
+                    None => ("404 Not Found", &b""[..]),
+                };
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    body.len()
+                );
+                let _ = stream.write_all(body);
+            }
+        });
+        url
+    }
+
+    /// A stand-in mirror that publishes `tarball` as the minirootfs of
+    /// `version`, with `signature` next to it when there is one.
+    fn mirror(version: &str, tarball: &[u8], signature: Option<&[u8]>) -> String {
+        let base = format!("/{}/releases/x86_64", branch(version));
+        let file = format!("alpine-minirootfs-{}-x86_64.tar.gz", version);
+        let sha256: String = Sha256::digest(tarball)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        let manifest = format!(
+            "- flavor: alpine-minirootfs\n  version: {}\n  file: {}\n  sha256: {}\n",
+            version, file, sha256
+        );
+        let mut files = HashMap::new();
+        files.insert(format!("{}/latest-releases.yaml", base), manifest.into());
+        files.insert(format!("{}/{}", base, file), tarball.to_vec());
+        if let Some(signature) = signature {
+            files.insert(format!("{}/{}.asc", base, file), signature.to_vec());
+        }
+        serve(files)
+    }
+
+    /// Caches `tarball` as `version` without a signature, along with a
+    /// rootfs that was extracted from it.
+    fn cache_unsigned(version: &str, tarball: &[u8]) {
+        fs::create_dir_all(rootfs_base_directory()).unwrap();
+        fs::write(rootfs_tarball(version, "x86_64"), tarball).unwrap();
+        let staging = PathBuf::from(format!(
+            "{}.staging",
+            rootfs_path(version, "x86_64").display()
+        ));
+        fs::create_dir_all(&staging).unwrap();
+        FsDriver::new()
+            .install_version(
+                &staging,
+                &rootfs_path(version, "x86_64"),
+                "0123456789abcdef",
+            )
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn download_keeps_tarballs_with_a_good_signature() {
+        let _cache = scratch_cache().await;
+        let mirrors = [mirror("3.97.1", SIGNED, Some(SIGNATURE))];
+        download_rootfs("3.97.1", "x86_64", &mirrors, true, false)
+            .await
+            .unwrap();
+        assert_eq!(
+            fs::read(rootfs_tarball("3.97.1", "x86_64")).unwrap(),
+            SIGNED
+        );
+        assert_eq!(
+            fs::read(rootfs_signature("3.97.1", "x86_64")).unwrap(),
+            SIGNATURE
+        );
+    }
+
+    #[tokio::test]
+    async fn download_discards_tarballs_with_a_bad_signature() {
+        let _cache = scratch_cache().await;
+        let tampered = mirror("3.97.1", TAMPERED, Some(SIGNATURE));
+        let unsigned = mirror("3.97.1", SIGNED, None);
+        assert!(
+            download_rootfs("3.97.1", "x86_64", &[tampered, unsigned], true, false)
+                .await
+                .is_err()
+        );
+        let tarball = rootfs_tarball("3.97.1", "x86_64");
+        assert!(!tarball.exists());
+        assert!(!PathBuf::from(format!("{}.part", tarball.display())).exists());
+        assert!(!rootfs_signature("3.97.1", "x86_64").exists());
+    }
+
+    #[tokio::test]
+    async fn download_verifies_cached_tarballs_without_a_signature() {
+        let _cache = scratch_cache().await;
+        cache_unsigned("3.97.1", SIGNED);
+        let mirrors = [mirror("3.97.1", SIGNED, Some(SIGNATURE))];
+        download_rootfs("3.97.1", "x86_64", &mirrors, true, false)
+            .await
+            .unwrap();
+        assert!(rootfs_tarball("3.97.1", "x86_64").exists());
+        assert_eq!(
+            fs::read(rootfs_signature("3.97.1", "x86_64")).unwrap(),
+            SIGNATURE
+        );
+        assert!(rootfs_path("3.97.1", "x86_64").exists());
+    }
+
+    #[tokio::test]
+    async fn download_discards_cached_tarballs_with_a_bad_signature() {
+        let _cache = scratch_cache().await;
+        cache_unsigned("3.97.1", TAMPERED);
+        let mirrors = [mirror("3.97.1", SIGNED, Some(SIGNATURE))];
+        assert!(download_rootfs("3.97.1", "x86_64", &mirrors, true, false)
+            .await
+            .is_err());
+        assert!(!rootfs_tarball("3.97.1", "x86_64").exists());
+        assert!(!rootfs_signature("3.97.1", "x86_64").exists());
+        assert!(fs::symlink_metadata(rootfs_path("3.97.1", "x86_64")).is_err());
+    }
+
+    #[tokio::test]
+    async fn resolve_version_keeps_explicit_versions() {
+        let mirrors = [];
+        for (version, resolved) in [
+            ("edge", "edge"),
+            ("3.18", "3.18"),
+            ("v3.18", "3.18"),
+            ("3.18.4", "3.18.4"),
+            ("v3.18.4", "3.18.4"),
+        ] {
+            assert_eq!(
+                resolve_version(version, "x86_64", &mirrors, true)
+                    .await
+                    .unwrap(),
+                resolved
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn resolve_version_rejects_garbage() {
+        let mirrors = [];
+        for version in ["3", "3.18.4.1", "3.x", "3..4", "not a version"] {
+            assert!(resolve_version(version, "x86_64", &mirrors, true)
+                .await
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn branch_of_versions() {
+        assert_eq!(branch("edge"), "edge");
+        assert_eq!(branch("3.18"), "v3.18");
+        assert_eq!(branch("3.18.4"), "v3.18");
+    }
+
+    #[test]
+    fn rolling_versions() {
+        assert!(is_rolling("edge"));
+        assert!(is_rolling("3.18"));
+        assert!(!is_rolling("3.18.4"));
+        assert!(!is_rolling("my-rootfs"));
+    }
 }
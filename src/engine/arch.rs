@@ -0,0 +1,80 @@
+use crate::util::{AtsiError, SyncResult};
+
+use std::fs;
+use std::path::PathBuf;
+
+/// Architectures that Alpine ships a minirootfs for, by Alpine's name for
+/// them.
+pub const SUPPORTED: [&str; 8] = [
+    "x86_64", "x86", "aarch64", "armv7", "armhf", "riscv64", "ppc64le", "s390x",
+];
+
+/// Turns an architecture name as given by the user or `uname -m` into the
+/// name Alpine uses for it, ex. `arm64` -> `aarch64`.
+pub fn normalize(arch: &str) -> SyncResult<&'static str> {
+    let normalized = match arch {
+        "x86_64" | "amd64" => "x86_64",
+        "x86" | "i386" | "i486" | "i586" | "i686" => "x86",
+        "aarch64" | "arm64" => "aarch64",
+        "armv7" | "armv7l" => "armv7",
+        "armhf" | "armv6l" => "armhf",
+        "riscv64" => "riscv64",
+        "ppc64le" => "ppc64le",
+        "s390x" => "s390x",
+        _ => return Err(Box::new(AtsiError::UnsupportedArch(arch.to_string()))),
+    };
+    Ok(normalized)
+}
+
+pub fn host() -> SyncResult<&'static str> {
+    let uname = nix::sys::utsname::uname()?;
+    normalize(&uname.machine().to_string_lossy())
+}
+
+/// Checks that binaries for `arch` can run on this host, either natively or
+/// through a qemu-user binfmt handler.
+pub fn check_runnable(arch: &str) -> SyncResult<()> {
+    let host = host()?;
+    if arch == host || (host == "x86_64" && arch == "x86") {
+        return Ok(());
+    }
+
+    let handler = binfmt_handler(arch);
+    let status = match fs::read_to_string(&handler) {
+        Ok(status) => status,
+        Err(_) => {
+            return Err(Box::new(AtsiError::BinfmtHandlerMissing(
+                arch.to_string(),
+                handler.display().to_string(),
+            )))
+        }
+    };
+    if !status.lines().any(|line| line == "enabled") {
+        return Err(Box::new(AtsiError::BinfmtHandlerMissing(
+            arch.to_string(),
+            handler.display().to_string(),
+        )));
+    }
+    // Without the F flag the kernel looks up the interpreter lazily, inside
+    // the container's rootfs, where it doesn't exist.
+    let fix_binary = status
+        .lines()
+        .filter_map(|line| line.strip_prefix("flags: "))
+        .any(|flags| flags.contains('F'));
+    if !fix_binary {
+        return Err(Box::new(AtsiError::BinfmtHandlerNotFixed(
+            handler.display().to_string(),
+        )));
+    }
+    debug!("running {} through {}", arch, handler.display());
+    Ok(())
+}
+
+fn binfmt_handler(arch: &str) -> PathBuf {
+    let qemu_arch = match arch {
+        "x86" => "i386",
+        "armv7" | "armhf" => "arm",
+        arch => arch,
+    };
+    PathBuf::from(format!("/proc/sys/fs/binfmt_misc/qemu-{}", qemu_arch))
+}
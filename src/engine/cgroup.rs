@@ -0,0 +1,247 @@
+use crate::util::{append_all, AtsiError, SyncResult};
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use nix::unistd::{access, AccessFlags, Pid};
+
+/// Resource limits for a container, applied through cgroup v2.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Default)]
+pub struct Limits {
+    /// Memory limit in bytes.
+    pub memory: Option<u64>,
+    /// CPU limit as a number of CPUs, ex. `1.5`.
+    pub cpus: Option<f64>,
+    pub pids: Option<u64>,
+}
+
+// cpu.max quotas are relative to this period, in microseconds.
+const CPU_PERIOD: u64 = 100_000;
+// The kernel refuses cpu.max quotas below 1ms.
+const CPU_MIN_QUOTA: f64 = 1000.0;
+
+impl Limits {
+    pub fn is_empty(&self) -> bool {
+        self.memory.is_none() && self.cpus.is_none() && self.pids.is_none()
+    }
+
+    fn controllers(&self) -> Vec<&'static str> {
+        let mut controllers = vec![];
+        if self.memory.is_some() {
+            controllers.push("memory");
+        }
+        if self.cpus.is_some() {
+            controllers.push("cpu");
+        }
+        if self.pids.is_some() {
+            controllers.push("pids");
+        }
+        controllers
+    }
+}
+
+impl std::fmt::Display for Limits {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut limits = vec![];
+        if let Some(memory) = self.memory {
+            limits.push(format!("memory={}", format_bytes(memory)));
+        }
+        if let Some(cpus) = self.cpus {
+            limits.push(format!("cpus={}", cpus));
+        }
+        if let Some(pids) = self.pids {
+            limits.push(format!("pids={}", pids));
+        }
+        write!(f, "{}", limits.join(" "))
+    }
+}
+
+/// Parses a memory size like `512M` or `2g` into bytes. Units are powers of
+/// 1024, and a bare number is taken as bytes.
+pub fn parse_memory(memory: &str) -> SyncResult<u64> {
+    let invalid = || -> Box<dyn std::error::Error + Send + Sync> {
+        Box::new(AtsiError::InvalidLimit(memory.to_string()))
+    };
+    let trimmed = memory.trim_end_matches(['b', 'B']);
+    let (number, multiplier) = match trimmed.chars().last() {
+        Some('k' | 'K') => (&trimmed[..trimmed.len() - 1], 1024),
+        Some('m' | 'M') => (&trimmed[..trimmed.len() - 1], 1024 * 1024),
+        Some('g' | 'G') => (&trimmed[..trimmed.len() - 1], 1024 * 1024 * 1024),
+        Some('t' | 'T') => (&trimmed[..trimmed.len() - 1], 1024 * 1024 * 1024 * 1024),
+        _ => (trimmed, 1),
+    };
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    number.checked_mul(multiplier).ok_or_else(invalid)
+}
+
+pub fn parse_cpus(cpus: &str) -> SyncResult<f64> {
+    match cpus.parse::<f64>() {
+        Ok(cpus) if cpus.is_finite() && cpus * CPU_PERIOD as f64 >= CPU_MIN_QUOTA => Ok(cpus),
+        _ => Err(Box::new(AtsiError::InvalidLimit(cpus.to_string()))),
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    for (unit, size) in [
+        ("T", 1u64 << 40),
+        ("G", 1 << 30),
+        ("M", 1 << 20),
+        ("K", 1 << 10),
+    ] {
+        if bytes >= size && bytes.is_multiple_of(size) {
+            return format!("{}{}", bytes / size, unit);
+        }
+    }
+    bytes.to_string()
+}
+
+/// Creates a cgroup for the container with the given limits, and moves
+/// `pid` into it. The cgroup lives under the highest cgroup that's been
+/// delegated to us, ex. systemd's `user@$UID.service`.
+pub fn create(name: &str, pid: Pid, limits: &Limits) -> SyncResult<PathBuf> {
+    let delegated = delegation_root()?;
+    debug!("using delegated cgroup {}", delegated.display());
+    let controllers = limits.controllers();
+
+    // Controllers have to be enabled at every level on the way down.
+    enable_controllers(&delegated, &controllers)?;
+    let parent = append_all(&delegated, vec!["atsi"]);
+    fs::create_dir_all(&parent)?;
+    enable_controllers(&parent, &controllers)?;
+
+    let cgroup = append_all(&parent, vec![name]);
+    fs::create_dir_all(&cgroup)?;
+    if let Some(memory) = limits.memory {
+        fs::write(append_all(&cgroup, vec!["memory.max"]), memory.to_string())?;
+    }
+    if let Some(cpus) = limits.cpus {
+        let quota = (cpus * CPU_PERIOD as f64) as u64;
+        fs::write(
+            append_all(&cgroup, vec!["cpu.max"]),
+            format!("{} {}", quota, CPU_PERIOD),
+        )?;
+    }
+    if let Some(pids) = limits.pids {
+        fs::write(append_all(&cgroup, vec!["pids.max"]), pids.to_string())?;
+    }
+    fs::write(procs_file(&cgroup), pid.to_string())?;
+    Ok(cgroup)
+}
+
+/// Removes a container's cgroup. This only works once everything inside of
+/// it has exited, which can take a moment after its init dies.
+pub fn remove(cgroup: &Path) -> SyncResult<()> {
+    let mut attempts = 0;
+    loop {
+        match fs::remove_dir(cgroup) {
+            Ok(_) => return Ok(()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(_) if attempts < 10 => {
+                attempts += 1;
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            Err(err) => return Err(Box::new(err)),
+        }
+    }
+}
+
+pub fn procs_file(cgroup: &Path) -> PathBuf {
+    append_all(cgroup, vec!["cgroup.procs"])
+}
+
+/// Walks up from our own cgroup for as long as we're allowed to move
+/// processes around, which is how delegation shows up in the filesystem.
+fn delegation_root() -> SyncResult<PathBuf> {
+    let mount = cgroup2_mount()?;
+    let own = own_cgroup(&mount)?;
+
+    let mut root = None;
+    let mut current = Some(own.as_path());
+    while let Some(cgroup) = current {
+        if !cgroup.starts_with(&mount) || access(&procs_file(cgroup), AccessFlags::W_OK).is_err() {
+            break;
+        }
+        root = Some(cgroup.to_path_buf());
+        current = cgroup.parent();
+    }
+    match root {
+        Some(root) => Ok(root),
+        None => Err(Box::new(AtsiError::NoDelegatedCgroup)),
+    }
+}
+
+fn cgroup2_mount() -> SyncResult<PathBuf> {
+    let mounts = fs::read_to_string("/proc/self/mounts")?;
+    for mount in mounts.lines() {
+        let fields: Vec<&str> = mount.split_whitespace().collect();
+        if fields.len() > 2 && fields[2] == "cgroup2" {
+            return Ok(PathBuf::from(fields[1]));
+        }
+    }
+    Err(Box::new(AtsiError::NoDelegatedCgroup))
+}
+
+fn own_cgroup(mount: &Path) -> SyncResult<PathBuf> {
+    let cgroups = fs::read_to_string("/proc/self/cgroup")?;
+    for cgroup in cgroups.lines() {
+        if let Some(path) = cgroup.strip_prefix("0::") {
+            return Ok(append_all(mount, vec![path.trim_start_matches('/')]));
+        }
+    }
+    Err(Box::new(AtsiError::NoDelegatedCgroup))
+}
+
+fn enable_controllers(cgroup: &Path, controllers: &[&str]) -> SyncResult<()> {
+    let available = fs::read_to_string(append_all(cgroup, vec!["cgroup.controllers"]))?;
+    for controller in controllers {
+        if !available.split_whitespace().any(|c| c == *controller) {
+            return Err(Box::new(AtsiError::CgroupControllerUnavailable(
+                controller.to_string(),
+            )));
+        }
+        fs::write(
+            append_all(cgroup, vec!["cgroup.subtree_control"]),
+            format!("+{}", controller),
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_memory_units() {
+        assert_eq!(parse_memory("1024").unwrap(), 1024);
+        assert_eq!(parse_memory("512k").unwrap(), 512 * 1024);
+        assert_eq!(parse_memory("512M").unwrap(), 512 * 1024 * 1024);
+        assert_eq!(parse_memory("2g").unwrap(), 2 * 1024 * 1024 * 1024);
+        assert_eq!(parse_memory("2GB").unwrap(), 2 * 1024 * 1024 * 1024);
+        assert_eq!(parse_memory("1T").unwrap(), 1 << 40);
+    }
+
+    #[test]
+    fn parse_memory_rejects_garbage() {
+        assert!(parse_memory("").is_err());
+        assert!(parse_memory("M").is_err());
+        assert!(parse_memory("-1M").is_err());
+        assert!(parse_memory("1.5G").is_err());
+        assert!(parse_memory("12X").is_err());
+        assert!(parse_memory("99999999999T").is_err());
+    }
+
+    #[test]
+    fn parse_cpus_limits() {
+        assert_eq!(parse_cpus("1").unwrap(), 1.0);
+        assert_eq!(parse_cpus("1.5").unwrap(), 1.5);
+        assert_eq!(parse_cpus("0.01").unwrap(), 0.01);
+        assert!(parse_cpus("0").is_err());
+        assert!(parse_cpus("-1").is_err());
+        assert!(parse_cpus("0.001").is_err());
+        assert!(parse_cpus("inf").is_err());
+        assert!(parse_cpus("NaN").is_err());
+        assert!(parse_cpus("lots").is_err());
+    }
+}
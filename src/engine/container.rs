@@ -1,39 +1,60 @@
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Write;
-use std::os::unix::prelude::CommandExt;
+use std::os::unix::prelude::{AsRawFd, CommandExt, ExitStatusExt, RawFd};
 use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
 use std::time::Duration;
 
 use super::fs_driver::FsDriver;
-use crate::util::{append_all, Result, SyncResult};
+use crate::util::{append_all, AtsiError, Result, SyncResult};
 
+use nix::mount::MsFlags;
 use nix::sched::{clone, CloneFlags};
-use nix::sys::wait::{waitpid, WaitStatus};
+use nix::sys::signal::{kill, SigSet, Signal};
+use nix::sys::signalfd::SignalFd;
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::Pid;
 use rlimit::Resource;
 use tokio::time::Instant;
 
+// Exit codes for when the container's command couldn't even be started,
+// following the conventions of shells and other container runtimes.
+const EXIT_SETUP_FAILED: i32 = 125;
+const EXIT_CANNOT_EXEC: i32 = 126;
+const EXIT_NOT_FOUND: i32 = 127;
+
 pub struct ContainerEngine {
     fs: FsDriver,
     opts: super::RunOpts,
+    /// Where a detached container reports that it started, see
+    /// `Engine::run_detached`.
+    ready_fd: Option<RawFd>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, derive_getters::Getters)]
 pub struct PersistentState {
     name: String,
     pid: u32,
+    container_pid: u32,
     slirp_pid: u32,
+    cgroup: Option<PathBuf>,
+    /// Ports forwarded by slirp4netns, keyed by the id it gave them.
+    port_forwards: Vec<(u32, super::slirp::PortForward)>,
     opts: super::RunOpts,
 }
 
 impl ContainerEngine {
-    pub fn new(opts: super::RunOpts) -> Self {
+    pub fn new(opts: super::RunOpts, ready_fd: Option<RawFd>) -> Self {
         Self {
             fs: FsDriver::new(),
             opts,
+            ready_fd,
         }
     }
 
-    pub async fn run(&mut self, start: Instant) -> SyncResult<()> {
+    /// Runs the container to completion, returning the exit code of its
+    /// command.
+    pub async fn run(&mut self, start: Instant) -> SyncResult<i32> {
         // Basic setup
         self.fs
             .touch_dir_sync(&self.fs.container_root(&self.opts.name))?;
@@ -47,11 +68,22 @@ impl ContainerEngine {
             }
         };
 
-        let callback = || match self.run_in_container(start) {
-            Ok(_) => 0,
-            Err(err) => {
-                error!("uncaught error! {}", err);
-                1
+        // The child blocks on this pipe until we've finished setting it up
+        // from the outside, ex. it can't mount anything before its uid/gid
+        // maps are written.
+        let (setup_rx, setup_tx) = nix::unistd::pipe()?;
+
+        let callback = || {
+            if let Err(err) = wait_for_setup(setup_rx, setup_tx) {
+                error!("container setup failed! {}", err);
+                return EXIT_SETUP_FAILED as isize;
+            }
+            match self.run_in_container(start) {
+                Ok(code) => code as isize,
+                Err(err) => {
+                    error!("uncaught error! {}", err);
+                    EXIT_SETUP_FAILED as isize
+                }
             }
         };
 
@@ -65,8 +97,7 @@ impl ContainerEngine {
                 | CloneFlags::CLONE_NEWUTS
                 | CloneFlags::CLONE_NEWNS
                 | CloneFlags::CLONE_NEWNET
-                | CloneFlags::CLONE_NEWUSER
-                | CloneFlags::CLONE_NEWCGROUP,
+                | CloneFlags::CLONE_NEWUSER,
             Some(nix::sys::signal::Signal::SIGCHLD as i32),
         )?;
         if (pid.as_raw() as i32) == -1 {
@@ -75,23 +106,42 @@ impl ContainerEngine {
             return Err(Box::new(std::io::Error::last_os_error()));
         }
 
-        // slirp4netns
-        let mut slirp =
-            super::slirp::spawn_for_container(&self.opts.name, pid.as_raw() as u32).await?;
-        self.persist(slirp.id().unwrap())?;
-        let ports = self.opts.ports.clone();
-        let name = self.opts.name.clone();
+        nix::unistd::close(setup_rx)?;
+        let (cgroup, network) = match self.setup_from_host(pid) {
+            Ok(cgroup) => (cgroup, self.setup_network(pid).await),
+            Err(err) => (None, Err(err)),
+        };
+        let (mut slirp, port_forwards) = match network {
+            Ok(network) => network,
+            Err(err) => {
+                // Closing the pipe without writing to it tells the child to
+                // bail. Its cgroup can only be removed once it's gone.
+                #[allow(unused_must_use)]
+                {
+                    nix::unistd::close(setup_tx);
+                    waitpid(pid, None);
+                    if let Some(cgroup) = &cgroup {
+                        super::cgroup::remove(cgroup);
+                    }
+                    self.fs.cleanup_root(&self.opts.name);
+                }
+                return Err(err);
+            }
+        };
         let slirp_id = slirp.id().unwrap();
+        self.persist(pid.as_raw() as u32, slirp_id, cgroup.clone(), port_forwards)?;
+        nix::unistd::write(setup_tx, &[0])?;
+        nix::unistd::close(setup_tx)?;
+        if let Some(ready_fd) = self.ready_fd.take() {
+            nix::unistd::write(ready_fd, &[0])?;
+            nix::unistd::close(ready_fd)?;
+        }
         tokio::spawn(async move {
-            for (outer, inner) in ports {
-                super::slirp::add_port_forward(&name, &outer, &inner)
-                    .await
-                    .unwrap();
-            }
             slirp.wait().await.unwrap();
         });
 
         let name = self.opts.name.clone();
+        let cgroup_for_handler = cgroup.clone();
         #[allow(unused_must_use)]
         ctrlc::set_handler(move || {
             debug!("cleaning up after ^C");
@@ -105,22 +155,29 @@ impl ContainerEngine {
                 nix::unistd::Pid::from_raw(slirp_id as i32),
                 nix::sys::signal::SIGTERM,
             );
+            if let Some(cgroup) = &cgroup_for_handler {
+                super::cgroup::remove(cgroup);
+            }
         })?;
 
         // wait for exit
-        loop {
+        let exit_code = loop {
             match waitpid(pid, None) {
-                Ok(WaitStatus::Exited(_pid, _status)) => {
-                    break;
+                Ok(WaitStatus::Exited(_pid, status)) => {
+                    break status;
+                }
+                Ok(WaitStatus::Signaled(_pid, signal, _core_dumped)) => {
+                    break 128 + signal as i32;
                 }
                 Err(nix::errno::Errno::ECHILD) => {
                     // We might need to wait to let stdout/err buffer
                     tokio::time::sleep(Duration::from_millis(100)).await;
-                    break;
+                    warn!("container exited, but its exit status was lost");
+                    break 1;
                 }
                 _ => tokio::time::sleep(Duration::from_millis(100)).await,
             }
-        }
+        };
 
         #[allow(unused_must_use)]
         {
@@ -133,12 +190,60 @@ impl ContainerEngine {
                 nix::unistd::Pid::from_raw(slirp_id as i32),
                 nix::sys::signal::SIGTERM,
             );
+            if let Some(cgroup) = &cgroup {
+                super::cgroup::remove(cgroup);
+            }
         }
 
-        Ok(())
+        Ok(exit_code)
+    }
+
+    /// Setup that has to be done from outside of the container before it
+    /// can start. Returns the container's cgroup, if it has one.
+    fn setup_from_host(&self, pid: Pid) -> SyncResult<Option<PathBuf>> {
+        super::idmap::map_ids(pid, self.opts.keep_id)?;
+        if self.opts.limits.is_empty() {
+            return Ok(None);
+        }
+        let cgroup = super::cgroup::create(&self.opts.name, pid, &self.opts.limits)?;
+        Ok(Some(cgroup))
+    }
+
+    /// Starts slirp4netns for the container and forwards its ports, so that
+    /// a port that can't be forwarded stops the container before its
+    /// command runs. Returns slirp4netns and the ids of the forwards.
+    async fn setup_network(
+        &self,
+        pid: Pid,
+    ) -> SyncResult<(tokio::process::Child, Vec<(u32, super::slirp::PortForward)>)> {
+        let mut slirp =
+            super::slirp::spawn_for_container(&self.opts.name, pid.as_raw() as u32).await?;
+        let mut port_forwards = vec![];
+        for forward in &self.opts.ports {
+            match super::slirp::add_port_forward(&self.opts.name, forward).await {
+                Ok(id) => {
+                    debug!("forwarding {} (id={})", forward, id);
+                    port_forwards.push((id, forward.clone()));
+                }
+                Err(err) => {
+                    #[allow(unused_must_use)]
+                    {
+                        slirp.kill().await;
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        Ok((slirp, port_forwards))
     }
 
-    fn persist(&self, slirp_pid: u32) -> SyncResult<()> {
+    fn persist(
+        &self,
+        container_pid: u32,
+        slirp_pid: u32,
+        cgroup: Option<PathBuf>,
+        port_forwards: Vec<(u32, super::slirp::PortForward)>,
+    ) -> SyncResult<()> {
         debug!(
             "persist state -> {}",
             self.fs.persistence_file(&self.opts.name).display()
@@ -146,33 +251,41 @@ impl ContainerEngine {
         let state = PersistentState {
             name: self.opts.name.clone(),
             pid: std::process::id(),
+            container_pid,
             slirp_pid,
+            cgroup,
+            port_forwards,
             opts: self.opts.clone(),
         };
         let ser = serde_json::to_string(&state)?;
-        let mut file = File::create(self.fs.persistence_file(&self.opts.name))?;
+        // Written next to it and then moved into place, so that `@ ps` never
+        // sees a half-written state file.
+        let persistence_file = self.fs.persistence_file(&self.opts.name);
+        let partial_file = persistence_file.with_extension("json.part");
+        let mut file = File::create(&partial_file)?;
         file.write_all(ser.as_bytes())?;
+        fs::rename(&partial_file, &persistence_file)?;
         Ok(())
     }
 
-    fn run_in_container(&mut self, start: Instant) -> Result<()> {
+    fn run_in_container(&mut self, start: Instant) -> Result<i32> {
         use nix::unistd::{chdir, chroot};
 
+        // This is only done now that we've been moved into our own cgroup,
+        // so that it becomes the root of the container's cgroup namespace.
+        nix::sched::unshare(CloneFlags::CLONE_NEWCGROUP)?;
+
         let container_root = &self.fs.container_root(&self.opts.name);
-        let rootfs_lower = &append_all(container_root, vec!["rootfs_lower"]);
         let rootfs = &append_all(container_root, vec!["rootfs"]);
 
-        // Set up root directory and bind-mount immutable alpine fs
+        // Nothing we mount from here on should propagate back to the host
+        debug!("making mounts private...");
+        self.fs.make_mounts_private()?;
+
+        // Set up root directory on top of the shared alpine fs
         debug!("setting up root directory...");
-        self.fs.touch_dir(rootfs_lower)?;
         self.fs.touch_dir(rootfs)?;
-        // Note: An overlay mount would be better. Since we've moved into a new
-        // mount namespace (CLONE_NEWNS), we should be able to do overlay
-        // mounts without issue. However, I just can't find the magic syscall
-        // combination that does an overlay mount right.
-        // If this could be figured out, @ would be a LOT faster.
-        super::alpine::extract_rootfs_to_path(&self.opts.alpine_version, rootfs_lower)?;
-        self.fs.bind_mount_rw(rootfs_lower, rootfs)?;
+        self.mount_rootfs(container_root, rootfs)?;
 
         // Mount basic devices
         debug!("bind-mounting devices...");
@@ -185,6 +298,41 @@ impl ContainerEngine {
         self.fs
             .bind_mount_dev("/dev/urandom", &append_all(rootfs, vec!["dev", "urandom"]))?;
 
+        // Mount kernel filesystems. We're already inside of the new pid
+        // namespace, so /proc only shows the container's own processes.
+        debug!("mounting kernel filesystems...");
+        let common_flags = MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC;
+        self.fs.mount_kernel_fs(
+            "proc",
+            &append_all(rootfs, vec!["proc"]),
+            common_flags,
+            None,
+        )?;
+        self.fs.mount_kernel_fs(
+            "sysfs",
+            &append_all(rootfs, vec!["sys"]),
+            common_flags | MsFlags::MS_RDONLY,
+            None,
+        )?;
+        // A private devpts instance, so that the container can't see or
+        // open the host's ptys.
+        self.fs.mount_kernel_fs(
+            "devpts",
+            &append_all(rootfs, vec!["dev", "pts"]),
+            MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC,
+            Some("newinstance,ptmxmode=0666,mode=0620"),
+        )?;
+        let ptmx = &append_all(rootfs, vec!["dev", "ptmx"]);
+        if ptmx.symlink_metadata().is_err() {
+            std::os::unix::fs::symlink("pts/ptmx", ptmx)?;
+        }
+        self.fs.mount_kernel_fs(
+            "tmpfs",
+            &append_all(rootfs, vec!["dev", "shm"]),
+            common_flags,
+            Some("mode=1777,size=65536k"),
+        )?;
+
         // Make a fake /tmp and mount it rw
         debug!("mounting /tmp...");
         let tmpfs = &append_all(container_root, vec!["tmp"]);
@@ -198,31 +346,51 @@ impl ContainerEngine {
         debug!("mounting user ro mounts...");
         self.auto_mount(rootfs, &self.opts.ro_mounts, AutoMountMode::Ro)?;
 
-        // chroot
+        if let Some(apk_cache) = &self.opts.apk_cache {
+            debug!("mounting shared apk cache...");
+            mount_apk_cache(&self.fs, apk_cache, rootfs)?;
+        }
+
+        // pivot_root
         debug!("pivoting!");
         debug!("pivotroot -> {}", rootfs.display());
-        chroot(rootfs).expect("couldn't chroot!?");
-        chdir("/app").expect("couldn't chdir to /app!?");
+        if let Err(err) = self.fs.pivot_root(rootfs) {
+            warn!("couldn't pivot_root, falling back to chroot: {}", err);
+            chroot(rootfs).expect("couldn't chroot!?");
+        }
 
         debug!("container started in: {:?}", start.elapsed());
 
         use std::process::Command;
 
-        if !self.opts.packages.is_empty() {
-            info!("installing {} package(s)...", self.opts.packages.len());
-            info!("requested packages: {}", self.opts.packages.join(", "));
-            let mut install_args = vec!["add", "-U"];
-            for pkg in &self.opts.packages {
-                install_args.push(pkg);
+        // Package installs should use the same mirror as the rootfs did.
+        if self.opts.set_repositories {
+            if let Some(repositories) =
+                super::alpine::repositories(&self.opts.alpine_mirror, &self.opts.alpine_version)
+            {
+                fs::write("/etc/apk/repositories", repositories)?;
             }
-            let _install_status = Command::new("/sbin/apk")
-                .env_clear()
-                .args(install_args)
-                .spawn()?
-                .wait()?;
-            info!("installed!");
         }
 
+        debug!("setting hostname to {}", self.opts.hostname);
+        nix::unistd::sethostname(&self.opts.hostname)?;
+        fs::write("/etc/hostname", format!("{}\n", self.opts.hostname))?;
+        let hosts = fs::read_to_string("/etc/hosts").ok();
+        fs::write(
+            "/etc/hosts",
+            hosts_file(hosts.as_deref(), &self.opts.hostname),
+        )?;
+
+        fs::create_dir_all(&self.opts.workdir)?;
+        chdir(self.opts.workdir.as_str())?;
+
+        // Users are set up before an immutable rootfs stops us from
+        // writing to /etc/passwd.
+        let user = match &self.opts.user {
+            Some(spec) => Some(super::user::ensure(spec)?),
+            None => None,
+        };
+
         if self.opts.immutable {
             info!("making container immutable!");
             debug!("remounting rootfs as ro!");
@@ -230,15 +398,59 @@ impl ContainerEngine {
             debug!("rootfs remounted ro!");
         }
 
-        // This will never return if the container successfully starts
-        let error = Command::new("sh")
-            .env_clear()
+        let mut command = Command::new("sh");
+        command.env_clear();
+        if let Some(user) = &user {
+            debug!("running as {} ({}:{})", user.name, user.uid, user.gid);
+            command
+                .env("HOME", &user.home)
+                .env("USER", &user.name)
+                .env("LOGNAME", &user.name)
+                .uid(user.uid)
+                .gid(user.gid);
+        }
+        command
             .envs(&self.opts.env_vars)
             .arg("-c")
-            .arg(&self.opts.command)
-            .exec();
+            .arg(&self.opts.command);
+        if self.opts.init {
+            return run_init(command);
+        }
+
+        // This will never return if the container successfully starts
+        let error = command.exec();
         error!("failed running container: {}", error);
 
+        Ok(exec_failure_exit_code(&error))
+    }
+
+    /// Mounts the shared lower rootfs at `rootfs`, with writes going to a
+    /// per-container upper directory. Falls back to fuse-overlayfs, and then
+    /// to a full copy of the rootfs, when kernel overlay mounts aren't
+    /// available to us.
+    fn mount_rootfs(&self, container_root: &Path, rootfs: &Path) -> Result<()> {
+        let lower = &self.opts.lower;
+        let upper = &append_all(container_root, vec!["upper"]);
+        let work = &append_all(container_root, vec!["work"]);
+        self.fs.touch_dir(upper)?;
+        self.fs.touch_dir(work)?;
+
+        // Unprivileged overlay mounts in user namespaces need Linux 5.11+.
+        if kernel_version() >= (5, 11) {
+            match self.fs.overlay_mount(lower, upper, work, rootfs) {
+                Ok(_) => return Ok(()),
+                Err(err) => debug!("kernel overlay mount failed: {}", err),
+            }
+        }
+        match self.fs.fuse_overlay_mount(lower, upper, work, rootfs) {
+            Ok(_) => return Ok(()),
+            Err(err) => debug!("fuse-overlayfs mount failed: {}", err),
+        }
+
+        warn!("overlay mounts aren't available, copying rootfs instead!");
+        let rootfs_lower = &append_all(container_root, vec!["rootfs_lower"]);
+        self.fs.copy_dir(lower, rootfs_lower)?;
+        self.fs.bind_mount_rw(rootfs_lower, rootfs)?;
         Ok(())
     }
 
@@ -290,8 +502,335 @@ impl ContainerEngine {
     }
 }
 
+/// Signals that the init passes on to the container's command. Everything
+/// else is either handled by the init itself, or can't be caught anyway.
+const FORWARDED_SIGNALS: [Signal; 9] = [
+    Signal::SIGHUP,
+    Signal::SIGINT,
+    Signal::SIGQUIT,
+    Signal::SIGTERM,
+    Signal::SIGUSR1,
+    Signal::SIGUSR2,
+    Signal::SIGALRM,
+    Signal::SIGCONT,
+    Signal::SIGWINCH,
+];
+
+/// A minimal tini-style init. We stay around as pid 1 of the container,
+/// run `command` as our child, forward signals to it, and reap any orphaned
+/// processes. Returns once `command` exits.
+fn run_init(mut command: std::process::Command) -> Result<i32> {
+    let mut signals = SigSet::empty();
+    for signal in FORWARDED_SIGNALS {
+        signals.add(signal);
+    }
+    signals.add(Signal::SIGCHLD);
+    // Blocked signals are queued up for the signalfd, instead of getting
+    // the usual pid 1 treatment of being dropped. The command itself is
+    // spawned with a clean signal mask.
+    signals.thread_block()?;
+    let mut signal_fd = SignalFd::new(&signals)?;
+
+    let child = match command.spawn() {
+        Ok(child) => Pid::from_raw(child.id() as i32),
+        Err(error) => {
+            error!("failed running container: {}", error);
+            return Ok(exec_failure_exit_code(&error));
+        }
+    };
+
+    loop {
+        let signal = match signal_fd.read_signal()? {
+            Some(info) => Signal::try_from(info.ssi_signo as i32)?,
+            None => continue,
+        };
+        if signal != Signal::SIGCHLD {
+            debug!("init: forwarding {} to pid {}", signal, child);
+            #[allow(unused_must_use)]
+            {
+                // The command may have just exited, in which case we'll
+                // get a SIGCHLD for it next.
+                kill(child, signal);
+            }
+            continue;
+        }
+
+        // SIGCHLDs get merged, so reap everything that's exited so far.
+        loop {
+            match waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+                Ok(WaitStatus::Exited(pid, code)) if pid == child => return Ok(code),
+                Ok(WaitStatus::Signaled(pid, signal, _)) if pid == child => {
+                    return Ok(128 + signal as i32)
+                }
+                Ok(WaitStatus::StillAlive) | Err(_) => break,
+                Ok(status) => debug!("init: reaped orphan {:?}", status.pid()),
+            }
+        }
+    }
+}
+
+fn exec_failure_exit_code(error: &std::io::Error) -> i32 {
+    if error.kind() == std::io::ErrorKind::NotFound {
+        EXIT_NOT_FOUND
+    } else {
+        EXIT_CANNOT_EXEC
+    }
+}
+
+/// Converts an exit status to a shell-style exit code, where death by a
+/// signal is reported as 128 + the signal number.
+fn exit_code(status: ExitStatus) -> i32 {
+    match status.code() {
+        Some(code) => code,
+        None => 128 + status.signal().unwrap_or(0),
+    }
+}
+
+/// Adds the container's hostname to its `/etc/hosts`, so that it resolves
+/// without going through DNS. Everything else in an existing file is kept,
+/// except for the hostname of a previous run, ex. in a committed image.
+fn hosts_file(existing: Option<&str>, hostname: &str) -> String {
+    let mut hosts: String = match existing {
+        Some(existing) => existing
+            .lines()
+            .filter(|line| line.split_whitespace().next() != Some("127.0.1.1"))
+            .map(|line| format!("{}\n", line))
+            .collect(),
+        None => "127.0.0.1\tlocalhost localhost.localdomain\n\
+                 ::1\tlocalhost localhost.localdomain\n"
+            .to_string(),
+    };
+    hosts.push_str(&format!("127.0.1.1\t{}\n", hostname));
+    hosts
+}
+
+pub fn wait_for_setup(setup_rx: RawFd, setup_tx: RawFd) -> Result<()> {
+    nix::unistd::close(setup_tx)?;
+    let mut buf = [0u8; 1];
+    let read = nix::unistd::read(setup_rx, &mut buf)?;
+    nix::unistd::close(setup_rx)?;
+    if read == 0 {
+        return Err(Box::new(AtsiError::ContainerSetupAborted));
+    }
+    Ok(())
+}
+
+/// Installs `packages` into `rootfs` with apk, chrooted inside of a user
+/// namespace. Unlike a container, this uses the host's network, so it
+/// doesn't need slirp4netns.
+pub fn install_packages(
+    rootfs: &Path,
+    packages: &[String],
+    repositories: Option<String>,
+    apk_cache: Option<&Path>,
+) -> SyncResult<()> {
+    let stack_size = match Resource::STACK.get() {
+        Ok((soft, _hard)) => soft as usize,
+        Err(_) => 8 * 1024 * 1024,
+    };
+    let (setup_rx, setup_tx) = nix::unistd::pipe()?;
+    let callback = || {
+        if let Err(err) = wait_for_setup(setup_rx, setup_tx) {
+            error!("package install setup failed! {}", err);
+            return EXIT_SETUP_FAILED as isize;
+        }
+        match run_apk_add(rootfs, packages, &repositories, apk_cache) {
+            Ok(code) => code as isize,
+            Err(err) => {
+                error!("couldn't install packages: {}", err);
+                EXIT_SETUP_FAILED as isize
+            }
+        }
+    };
+
+    let mut stack_vec = vec![0u8; stack_size];
+    let pid = clone(
+        Box::new(callback),
+        stack_vec.as_mut_slice(),
+        CloneFlags::CLONE_NEWPID | CloneFlags::CLONE_NEWNS | CloneFlags::CLONE_NEWUSER,
+        Some(nix::sys::signal::Signal::SIGCHLD as i32),
+    )?;
+    nix::unistd::close(setup_rx)?;
+    let setup = super::idmap::map_ids(pid, false);
+    if setup.is_ok() {
+        nix::unistd::write(setup_tx, &[0])?;
+    }
+    nix::unistd::close(setup_tx)?;
+    let status = waitpid(pid, None)?;
+    setup?;
+
+    match status {
+        WaitStatus::Exited(_pid, 0) => Ok(()),
+        WaitStatus::Exited(_pid, code) => Err(Box::new(AtsiError::PackageInstallFailed(code))),
+        WaitStatus::Signaled(_pid, signal, _core_dumped) => Err(Box::new(
+            AtsiError::PackageInstallFailed(128 + signal as i32),
+        )),
+        _ => Err(Box::new(AtsiError::PackageInstallFailed(EXIT_SETUP_FAILED))),
+    }
+}
+
+fn run_apk_add(
+    rootfs: &Path,
+    packages: &[String],
+    repositories: &Option<String>,
+    apk_cache: Option<&Path>,
+) -> Result<i32> {
+    use nix::unistd::{chdir, chroot};
+
+    let fs = FsDriver::new();
+    fs.make_mounts_private()?;
+    for dev in ["/dev/null", "/dev/zero", "/dev/random", "/dev/urandom"] {
+        fs.bind_mount_dev(dev, &append_all(rootfs, vec![&dev[1..]]))?;
+    }
+    fs.mount_kernel_fs(
+        "proc",
+        &append_all(rootfs, vec!["proc"]),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+        None,
+    )?;
+    // The rootfs points at slirp4netns' DNS server, which doesn't exist here.
+    fs.bind_mount_ro(
+        Path::new("/etc/resolv.conf"),
+        &append_all(rootfs, vec!["etc", "resolv.conf"]),
+    )?;
+    if let Some(apk_cache) = apk_cache {
+        mount_apk_cache(&fs, apk_cache, rootfs)?;
+    }
+    chroot(rootfs)?;
+    chdir("/")?;
+    if let Some(repositories) = repositories {
+        fs::write("/etc/apk/repositories", repositories)?;
+    }
+
+    let status = std::process::Command::new("/sbin/apk")
+        .env_clear()
+        .env("PATH", "/usr/sbin:/usr/bin:/sbin:/bin")
+        .args(["add", "-U"])
+        .args(packages)
+        .status()?;
+    Ok(exit_code(status))
+}
+
+/// apk caches everything it downloads in `/etc/apk/cache` if that exists, so
+/// mounting a shared directory there lets containers reuse each other's
+/// downloads.
+fn mount_apk_cache(fs: &FsDriver, apk_cache: &Path, rootfs: &Path) -> Result<()> {
+    std::fs::create_dir_all(apk_cache)?;
+    let target = append_all(rootfs, vec!["etc", "apk", "cache"]);
+    fs.touch_dir(&target)?;
+    fs.bind_mount_rw(apk_cache, &target)
+}
+
+fn kernel_version() -> (u32, u32) {
+    let release = match nix::sys::utsname::uname() {
+        Ok(uname) => uname.release().to_string_lossy().to_string(),
+        Err(_) => return (0, 0),
+    };
+    let mut parts = release
+        .split(|c: char| !c.is_ascii_digit())
+        .map(|part| part.parse().unwrap_or(0));
+    (parts.next().unwrap_or(0), parts.next().unwrap_or(0))
+}
+
+/// Runs `command` inside of an already-running container by joining all of
+/// its namespaces and its root directory, returning its exit code.
+pub fn exec_in_container(state: &PersistentState, command: &str) -> SyncResult<i32> {
+    use nix::sched::setns;
+    use nix::unistd::{chroot, fchdir, fork, ForkResult};
+
+    let proc_dir = PathBuf::from(format!("/proc/{}", state.container_pid()));
+    // The user namespace has to come first, as it's what grants us the
+    // capabilities needed to join the rest.
+    let mut namespaces = vec![];
+    for (ns, flag) in [
+        ("user", CloneFlags::CLONE_NEWUSER),
+        ("mnt", CloneFlags::CLONE_NEWNS),
+        ("uts", CloneFlags::CLONE_NEWUTS),
+        ("net", CloneFlags::CLONE_NEWNET),
+        ("cgroup", CloneFlags::CLONE_NEWCGROUP),
+        ("pid", CloneFlags::CLONE_NEWPID),
+    ] {
+        namespaces.push((File::open(append_all(&proc_dir, vec!["ns", ns]))?, flag));
+    }
+    // These have to be opened up-front, since the host's /proc isn't
+    // reachable anymore once we're in the container's mount namespace.
+    let root = File::open(append_all(&proc_dir, vec!["root"]))?;
+    let cwd = File::open(append_all(&proc_dir, vec!["cwd"]))?;
+    let cgroup_procs = match state.cgroup() {
+        Some(cgroup) => Some(
+            fs::OpenOptions::new()
+                .write(true)
+                .open(super::cgroup::procs_file(cgroup))?,
+        ),
+        None => None,
+    };
+
+    let mut exec = std::process::Command::new("sh");
+    exec.env_clear()
+        .envs(&state.opts().env_vars)
+        .arg("-c")
+        .arg(command);
+    unsafe {
+        // setns(2) can't be used from a multithreaded process, so this all
+        // has to happen in the forked child.
+        exec.pre_exec(move || {
+            // Writing 0 moves the writing process itself, so that the
+            // container's resource limits apply to us too.
+            if let Some(procs) = &cgroup_procs {
+                nix::unistd::write(procs.as_raw_fd(), b"0")?;
+            }
+            for (ns, flag) in &namespaces {
+                setns(ns.as_raw_fd(), *flag)?;
+            }
+            fchdir(root.as_raw_fd())?;
+            chroot(".")?;
+            fchdir(cwd.as_raw_fd())?;
+
+            // Joining a pid namespace only applies to children, so we have
+            // to fork once more. The intermediate process just mirrors the
+            // exit status of the real one.
+            match fork()? {
+                ForkResult::Parent { child } => {
+                    let code = match waitpid(child, None) {
+                        Ok(WaitStatus::Exited(_, code)) => code,
+                        Ok(WaitStatus::Signaled(_, signal, _)) => 128 + signal as i32,
+                        _ => 1,
+                    };
+                    nix::libc::_exit(code)
+                }
+                ForkResult::Child => Ok(()),
+            }
+        });
+    }
+    Ok(exit_code(exec.status()?))
+}
+
 #[derive(Debug)]
 enum AutoMountMode {
     Rw,
     Ro,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hosts_file_without_an_existing_one() {
+        assert_eq!(
+            hosts_file(None, "web"),
+            "127.0.0.1\tlocalhost localhost.localdomain\n\
+             ::1\tlocalhost localhost.localdomain\n\
+             127.0.1.1\tweb\n"
+        );
+    }
+
+    #[test]
+    fn hosts_file_keeps_existing_entries() {
+        let existing = "127.0.0.1\tlocalhost\n127.0.1.10\tother\n127.0.1.1\told\n";
+        assert_eq!(
+            hosts_file(Some(existing), "web"),
+            "127.0.0.1\tlocalhost\n127.0.1.10\tother\n127.0.1.1\tweb\n"
+        );
+    }
+}
@@ -1,9 +1,15 @@
-use crate::util::{append_all, Result, SyncResult};
+use crate::util::{append_all, AtsiError, Result, SyncResult};
 
-use std::fs::{self, OpenOptions};
+use std::fs::{self, File, OpenOptions, Permissions};
+use std::os::unix::prelude::{MetadataExt, PermissionsExt};
 use std::path::{Path, PathBuf};
+use std::process::Command;
 
-use nix::mount::{mount, MsFlags};
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use nix::unistd::{chdir, pivot_root};
+
+// Versioned directories keep their versions in here, next to the pointer.
+const VERSIONS_DIRECTORY: &str = ".versions";
 
 pub struct FsDriver;
 
@@ -25,8 +31,156 @@ impl FsDriver {
         append_all(&self.container_root(name), vec!["state.json"])
     }
 
+    pub fn stdout_log(&self, name: &str) -> PathBuf {
+        append_all(&self.container_root(name), vec!["stdout.log"])
+    }
+
+    pub fn stderr_log(&self, name: &str) -> PathBuf {
+        append_all(&self.container_root(name), vec!["stderr.log"])
+    }
+
+    /// Cleans up after a container that has exited. A detached container's
+    /// logs are left behind, so that it's possible to find out why it
+    /// exited, until it's removed with `remove_root`.
     pub fn cleanup_root(&self, name: &str) -> SyncResult<()> {
-        fs::remove_dir_all(self.container_root(name))?;
+        let logs = [self.stdout_log(name), self.stderr_log(name)];
+        if !logs.iter().any(|log| log.exists()) {
+            return self.remove_root(name);
+        }
+        self.unlock_overlay_work(name)?;
+        for entry in fs::read_dir(self.container_root(name))? {
+            let entry = entry?;
+            if logs.contains(&entry.path()) {
+                continue;
+            }
+            if entry.file_type()?.is_dir() {
+                super::idmap::remove_dir_all(&entry.path())?;
+            } else {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes everything in the container's root, including its logs.
+    pub fn remove_root(&self, name: &str) -> SyncResult<()> {
+        self.unlock_overlay_work(name)?;
+        super::idmap::remove_dir_all(&self.container_root(name))
+    }
+
+    fn unlock_overlay_work(&self, name: &str) -> SyncResult<()> {
+        // overlayfs creates its inner work directory with mode 000, which
+        // would stop us from removing it.
+        let overlay_work = append_all(&self.container_root(name), vec!["work", "work"]);
+        if overlay_work.exists() {
+            fs::set_permissions(&overlay_work, Permissions::from_mode(0o700))?;
+        }
+        Ok(())
+    }
+
+    pub fn overlay_mount(
+        &self,
+        lower: &Path,
+        upper: &Path,
+        work: &Path,
+        target: &Path,
+    ) -> Result<()> {
+        debug!("overlay-mount {} -> {}", lower.display(), target.display());
+        mount(
+            Some("overlay"),
+            target,
+            Some("overlay"),
+            MsFlags::empty(),
+            Some(format!("userxattr,{}", overlay_options(lower, upper, work)).as_str()),
+        )?;
+        Ok(())
+    }
+
+    pub fn fuse_overlay_mount(
+        &self,
+        lower: &Path,
+        upper: &Path,
+        work: &Path,
+        target: &Path,
+    ) -> Result<()> {
+        debug!(
+            "fuse-overlayfs-mount {} -> {}",
+            lower.display(),
+            target.display()
+        );
+        let status = Command::new("fuse-overlayfs")
+            .arg("-o")
+            .arg(overlay_options(lower, upper, work))
+            .arg(target)
+            .status()?;
+        if !status.success() {
+            return Err(Box::new(AtsiError::FuseOverlayFailed(status)));
+        }
+        Ok(())
+    }
+
+    pub fn copy_dir(&self, src: &Path, target: &Path) -> std::io::Result<()> {
+        self.copy_tree(src, target, &[], false)
+    }
+
+    /// Like `copy_dir`, but the paths in `skip` are left empty, ex. so that
+    /// mount points aren't copied along with the rest of a container.
+    /// Sockets, fifos and device nodes are skipped too, since they can't be
+    /// copied meaningfully.
+    ///
+    /// Everything keeps its owner, so this has to run inside of a user
+    /// namespace that maps the owners.
+    pub fn copy_dir_except(
+        &self,
+        src: &Path,
+        target: &Path,
+        skip: &[PathBuf],
+    ) -> std::io::Result<()> {
+        self.copy_tree(src, target, skip, true)
+    }
+
+    fn copy_tree(
+        &self,
+        src: &Path,
+        target: &Path,
+        skip: &[PathBuf],
+        keep_owner: bool,
+    ) -> std::io::Result<()> {
+        fs::create_dir_all(target)?;
+        for entry in fs::read_dir(src)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            let entry_target = append_all(target, vec![&entry.file_name().to_string_lossy()]);
+            if skip.contains(&entry.path()) {
+                debug!("skipping {}", entry.path().display());
+                if file_type.is_dir() {
+                    fs::create_dir_all(&entry_target)?;
+                } else {
+                    File::create(&entry_target)?;
+                }
+            } else if file_type.is_dir() {
+                self.copy_tree(&entry.path(), &entry_target, skip, keep_owner)?;
+            } else if file_type.is_symlink() {
+                std::os::unix::fs::symlink(fs::read_link(entry.path())?, &entry_target)?;
+                if keep_owner {
+                    copy_owner(&entry.path(), &entry_target)?;
+                }
+            } else if file_type.is_file() {
+                fs::copy(entry.path(), &entry_target)?;
+                if keep_owner {
+                    copy_owner(&entry.path(), &entry_target)?;
+                    // Changing the owner clears setuid and setgid bits.
+                    fs::set_permissions(&entry_target, entry.metadata()?.permissions())?;
+                }
+            } else {
+                debug!("skipping special file {}", entry.path().display());
+            }
+        }
+        if keep_owner {
+            copy_owner(src, target)?;
+        }
+        // Permissions go last, in case the directory isn't writable.
+        fs::set_permissions(target, fs::metadata(src)?.permissions())?;
         Ok(())
     }
 
@@ -35,6 +189,36 @@ impl FsDriver {
         Ok(())
     }
 
+    pub fn make_mounts_private(&self) -> Result<()> {
+        mount::<str, str, str, str>(None, "/", None, MsFlags::MS_REC | MsFlags::MS_PRIVATE, None)?;
+        Ok(())
+    }
+
+    /// Makes `new_root` the root of the current mount namespace, and detaches
+    /// the old root so that the host's filesystem isn't reachable anymore.
+    /// `new_root` must be a mount point.
+    pub fn pivot_root(&self, new_root: &Path) -> Result<()> {
+        // Pivoting onto "." stacks the old root on top of the new one, which
+        // saves us from needing a temporary directory to put it in.
+        chdir(new_root)?;
+        pivot_root(".", ".")?;
+        umount2(".", MntFlags::MNT_DETACH)?;
+        chdir("/")?;
+        Ok(())
+    }
+
+    pub fn mount_kernel_fs(
+        &self,
+        fstype: &str,
+        target: &Path,
+        flags: MsFlags,
+        data: Option<&str>,
+    ) -> Result<()> {
+        debug!("mount {} -> {}", fstype, target.display());
+        mount(Some(fstype), target, Some(fstype), flags, data)?;
+        Ok(())
+    }
+
     #[allow(dead_code)]
     pub fn bind_mount_ro(&self, src: &Path, target: &Path) -> Result<()> {
         // ro bindmount is a complicated procedure: https://unix.stackexchange.com/a/128388
@@ -73,7 +257,12 @@ impl FsDriver {
 
     pub fn touch(&self, path: &Path) -> Result<()> {
         debug!("touching: {}", path.display());
-        match OpenOptions::new().create(true).write(true).open(path) {
+        match OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)
+        {
             Ok(_) => Ok(()),
             Err(e) => Err(Box::new(e)),
         }
@@ -94,4 +283,183 @@ impl FsDriver {
             Err(e) => Err(Box::new(e)),
         }
     }
+
+    /// Where `version` of the versioned directory `pointer` is kept.
+    /// `pointer` itself is a symlink to its current version, so that a
+    /// cached rootfs can be replaced without pulling it out from under the
+    /// containers that are mounted on top of it.
+    pub fn version_path(&self, pointer: &Path, version: &str) -> PathBuf {
+        let mut path = pointer.with_file_name(VERSIONS_DIRECTORY);
+        path.push(format!("{}.{}", file_name(pointer), version));
+        path
+    }
+
+    /// The version that `pointer` currently points to, if any.
+    pub fn current_version(&self, pointer: &Path) -> Option<String> {
+        let target = fs::read_link(pointer).ok()?;
+        file_name(&target)
+            .strip_prefix(&format!("{}.", file_name(pointer)))
+            .map(|version| version.to_string())
+    }
+
+    /// Moves `staging` into place as `version` of `pointer`, and points
+    /// `pointer` at it. Versions that are no longer current are removed,
+    /// unless a running container is still using them.
+    pub fn install_version(&self, staging: &Path, pointer: &Path, version: &str) -> SyncResult<()> {
+        let target = self.version_path(pointer, version);
+        fs::create_dir_all(target.parent().unwrap())?;
+        if let Err(err) = fs::rename(staging, &target) {
+            // Someone else already installed the same version.
+            if !target.exists() {
+                return Err(Box::new(err));
+            }
+            super::idmap::remove_dir_all(staging)?;
+        }
+        self.move_unversioned(pointer)?;
+
+        // Swapping a new symlink into place means that the pointer is never
+        // missing, not even for a moment.
+        let link = PathBuf::from(format!("{}.link.{}", pointer.display(), std::process::id()));
+        let _ = fs::remove_file(&link);
+        std::os::unix::fs::symlink(
+            append_all(
+                &PathBuf::from(VERSIONS_DIRECTORY),
+                vec![&file_name(&target)],
+            ),
+            &link,
+        )?;
+        fs::rename(&link, pointer)?;
+        self.prune_versions(pointer)
+    }
+
+    /// Removes `pointer`, along with every version of it that isn't in use.
+    pub fn drop_version(&self, pointer: &Path) -> SyncResult<()> {
+        self.move_unversioned(pointer)?;
+        match fs::remove_file(pointer) {
+            Ok(_) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(Box::new(err)),
+        }
+        self.prune_versions(pointer)
+    }
+
+    /// Removes the versions of `pointer` that it doesn't point to and that
+    /// no running container is using.
+    pub fn prune_versions(&self, pointer: &Path) -> SyncResult<()> {
+        let versions = pointer.with_file_name(VERSIONS_DIRECTORY);
+        let entries = match fs::read_dir(&versions) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(Box::new(err)),
+        };
+        let current = fs::canonicalize(pointer).ok();
+        let in_use = self.lowers_in_use();
+        let prefix = format!("{}.", file_name(pointer));
+        for entry in entries {
+            let path = entry?.path();
+            // Versions never contain dots, which keeps ex. `3.18` from
+            // matching the versions of `3.18.4`.
+            let unversioned = match file_name(&path).strip_prefix(&prefix) {
+                Some(version) if !version.contains('.') => version == "unversioned",
+                _ => continue,
+            };
+            let path = fs::canonicalize(&path)?;
+            // Containers that were started before the cache was versioned
+            // recorded the pointer itself.
+            if current.as_ref() == Some(&path)
+                || in_use.iter().any(|lower| {
+                    lower.starts_with(&path) || (unversioned && lower.starts_with(pointer))
+                })
+            {
+                continue;
+            }
+            debug!("removing unused version {}", path.display());
+            super::idmap::remove_dir_all(&path)?;
+        }
+        Ok(())
+    }
+
+    /// `prune_versions` for every versioned directory in `directory`,
+    /// including ones whose pointer is gone.
+    pub fn prune_all_versions(&self, directory: &Path) -> SyncResult<()> {
+        let entries = match fs::read_dir(append_all(directory, vec![VERSIONS_DIRECTORY])) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(Box::new(err)),
+        };
+        let mut pointers = vec![];
+        for entry in entries {
+            if let Some((pointer, _)) = file_name(&entry?.path()).rsplit_once('.') {
+                let pointer = append_all(directory, vec![pointer]);
+                if !pointers.contains(&pointer) {
+                    pointers.push(pointer);
+                }
+            }
+        }
+        for pointer in pointers {
+            self.prune_versions(&pointer)?;
+        }
+        Ok(())
+    }
+
+    /// Caches from before versioning was introduced are plain directories;
+    /// they're turned into a version so that they're pruned like any other.
+    fn move_unversioned(&self, pointer: &Path) -> SyncResult<()> {
+        match fs::symlink_metadata(pointer) {
+            Ok(metadata) if metadata.is_dir() => {
+                let target = self.version_path(pointer, "unversioned");
+                fs::create_dir_all(target.parent().unwrap())?;
+                fs::rename(pointer, target)?;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The lower directories of every container that's still running.
+    fn lowers_in_use(&self) -> Vec<PathBuf> {
+        let mut lowers = vec![];
+        let containers = match fs::read_dir(self.all_containers_root()) {
+            Ok(containers) => containers,
+            Err(_) => return lowers,
+        };
+        for container in containers.flatten() {
+            let state = self.persistence_file(&container.file_name().to_string_lossy());
+            let state: super::container::PersistentState = match fs::read_to_string(state)
+                .ok()
+                .and_then(|state| serde_json::from_str(&state).ok())
+            {
+                Some(state) => state,
+                None => continue,
+            };
+            if PathBuf::from(format!("/proc/{}", state.pid())).exists() {
+                let lower = &state.opts().lower;
+                if let Ok(canonical) = fs::canonicalize(lower) {
+                    lowers.push(canonical);
+                }
+                lowers.push(lower.clone());
+            }
+        }
+        lowers
+    }
+}
+
+fn copy_owner(src: &Path, target: &Path) -> std::io::Result<()> {
+    let metadata = fs::symlink_metadata(src)?;
+    std::os::unix::fs::lchown(target, Some(metadata.uid()), Some(metadata.gid()))
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+fn overlay_options(lower: &Path, upper: &Path, work: &Path) -> String {
+    format!(
+        "lowerdir={},upperdir={},workdir={}",
+        lower.display(),
+        upper.display(),
+        work.display()
+    )
 }
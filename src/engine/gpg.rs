@@ -0,0 +1,291 @@
+use crate::util::{append_all, cache_dir, AtsiError, SyncResult};
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use base64::Engine;
+
+/// Alpine's release signing keys, bundled with @ and pinned by fingerprint.
+/// A signature is only accepted when gpgv reports that it was made by one of
+/// these fingerprints.
+pub const ALPINE_RELEASE_KEYS: [(&str, &str); 1] = [(
+    // Natanael Copa <ncopa@alpinelinux.org>
+    "0482D84022F52DF1C4E7CD43293ACD0907D9495A",
+    include_str!("keys/ncopa.asc"),
+)];
+
+/// The keys that `verify_alpine_signature` trusts. Tests sign with a key of
+/// their own, since they can't sign with Alpine's.
+#[cfg(not(test))]
+const TRUSTED_KEYS: &[(&str, &str)] = &ALPINE_RELEASE_KEYS;
+#[cfg(test)]
+const TRUSTED_KEYS: &[(&str, &str)] = &[(tests::TEST_FINGERPRINT, tests::TEST_KEY)];
+
+fn keys_directory() -> PathBuf {
+    append_all(&cache_dir(), vec!["keys"])
+}
+
+/// Checks that `signature` is a valid signature of `file`, made by one of
+/// Alpine's release signing keys.
+pub fn verify_alpine_signature(file: &Path, signature: &Path) -> SyncResult<()> {
+    let mut keyrings = vec![];
+    let mut fingerprints = vec![];
+    for &(fingerprint, armored) in TRUSTED_KEYS {
+        keyrings.push(write_keyring(&keys_directory(), fingerprint, armored)?);
+        fingerprints.push(fingerprint);
+    }
+    verify_signature(file, signature, &keyrings, &fingerprints)
+}
+
+/// Checks `signature` against `file` with gpgv, only accepting signatures
+/// made by keys with the given fingerprints.
+pub fn verify_signature(
+    file: &Path,
+    signature: &Path,
+    keyrings: &[PathBuf],
+    trusted_fingerprints: &[&str],
+) -> SyncResult<()> {
+    let mut gpgv = Command::new("gpgv");
+    gpgv.arg("--status-fd").arg("1");
+    for keyring in keyrings {
+        gpgv.arg("--keyring").arg(keyring);
+    }
+    let output = match gpgv.arg(signature).arg(file).stderr(Stdio::null()).output() {
+        Ok(output) => output,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return Err(Box::new(AtsiError::GpgvMissing))
+        }
+        Err(err) => return Err(Box::new(err)),
+    };
+
+    let status = String::from_utf8_lossy(&output.stdout);
+    match trusted_signer(&status, trusted_fingerprints) {
+        Some(signer) if output.status.success() => {
+            debug!("{} has a good signature from {}", file.display(), signer);
+            Ok(())
+        }
+        _ => Err(Box::new(AtsiError::SignatureInvalid(
+            file.display().to_string(),
+        ))),
+    }
+}
+
+/// Finds the trusted key that gpgv's `--status-fd` output says made a valid
+/// signature, if any.
+fn trusted_signer<'a>(status: &str, trusted_fingerprints: &[&'a str]) -> Option<&'a str> {
+    // VALIDSIG <signing key fingerprint> ... <primary key fingerprint>
+    status
+        .lines()
+        .filter_map(|line| line.strip_prefix("[GNUPG:] VALIDSIG "))
+        .find_map(|fields| {
+            let fields: Vec<&str> = fields.split_whitespace().collect();
+            trusted_fingerprints
+                .iter()
+                .find(|trusted| {
+                    fields.first().map(|f| trusted.eq_ignore_ascii_case(f)) == Some(true)
+                        || fields.last().map(|f| trusted.eq_ignore_ascii_case(f)) == Some(true)
+                })
+                .copied()
+        })
+}
+
+/// Writes a binary keyring for an armored key into `directory`, since gpgv
+/// doesn't understand armored ones.
+fn write_keyring(directory: &Path, fingerprint: &str, armored: &str) -> SyncResult<PathBuf> {
+    let keyring = append_all(directory, vec![&format!("{}.gpg", fingerprint)]);
+    let partial = append_all(
+        directory,
+        vec![&format!("{}.gpg.{}", fingerprint, std::process::id())],
+    );
+    fs::create_dir_all(directory)?;
+    fs::write(&partial, dearmor(armored)?)?;
+    fs::rename(&partial, &keyring)?;
+    Ok(keyring)
+}
+
+/// Strips the ASCII armor off of an OpenPGP public key block.
+fn dearmor(armored: &str) -> SyncResult<Vec<u8>> {
+    let mut lines = armored
+        .lines()
+        .map(str::trim)
+        .skip_while(|line| !line.starts_with("-----BEGIN PGP PUBLIC KEY BLOCK-----"))
+        .skip(1);
+    // Armor headers end at the first blank line.
+    for line in lines.by_ref() {
+        if line.is_empty() {
+            break;
+        }
+    }
+    // Everything up to the CRC line or the footer is the key itself.
+    let body: String = lines
+        .take_while(|line| !line.starts_with('=') && !line.starts_with("-----"))
+        .collect();
+    if body.is_empty() {
+        return Err(Box::new(AtsiError::SigningKeyInvalid));
+    }
+    Ok(base64::engine::general_purpose::STANDARD.decode(body)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::scratch::ScratchDirectory;
+
+    // A key pair made just for these tests, and one that isn't trusted.
+    pub(super) const TEST_KEY: &str = include_str!("testdata/test-key.asc");
+    pub(super) const TEST_FINGERPRINT: &str = "037EFDE1C23B05F704D328F9C6CF8330EBD818D8";
+    const UNTRUSTED_KEY: &str = include_str!("testdata/untrusted-key.asc");
+    const UNTRUSTED_FINGERPRINT: &str = "389C19C7951FC5207665E4EBE015728F8C0E7AC3";
+
+    fn testdata(file: &str) -> PathBuf {
+        append_all(
+            Path::new(env!("CARGO_MANIFEST_DIR")),
+            vec!["src", "engine", "testdata", file],
+        )
+    }
+
+    /// Lists the fingerprints of the primary keys in an armored key block.
+    fn fingerprints(armored: &str) -> Vec<String> {
+        let directory = ScratchDirectory::new("gpg");
+        let key = append_all(&directory, vec!["key.asc"]);
+        fs::write(&key, armored).unwrap();
+        let output = Command::new("gpg")
+            .arg("--homedir")
+            .arg(&directory)
+            .args(["--batch", "--with-colons", "--show-keys"])
+            .arg(&key)
+            .output()
+            .unwrap();
+        let mut is_primary = false;
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| {
+                let fields: Vec<&str> = line.split(':').collect();
+                match fields[0] {
+                    "pub" => is_primary = true,
+                    "sub" => is_primary = false,
+                    "fpr" if is_primary => return fields.get(9).map(|f| f.to_string()),
+                    _ => {}
+                }
+                None
+            })
+            .collect()
+    }
+
+    #[test]
+    fn bundled_keys_match_their_fingerprints() {
+        assert_eq!(fingerprints(TEST_KEY), vec![TEST_FINGERPRINT.to_string()]);
+        for (fingerprint, armored) in ALPINE_RELEASE_KEYS {
+            assert_eq!(fingerprints(armored), vec![fingerprint.to_string()]);
+        }
+    }
+
+    #[test]
+    fn dearmor_decodes_the_key_packets() {
+        let key = dearmor(TEST_KEY).unwrap();
+        // An old-format public key packet, tag 6.
+        assert_eq!(key[0] & 0b1011_1100, 0b1001_1000);
+
+        let with_headers = TEST_KEY.replacen(
+            "-----BEGIN PGP PUBLIC KEY BLOCK-----\n",
+            "-----BEGIN PGP PUBLIC KEY BLOCK-----\nComment: a test key\n",
+            1,
+        );
+        assert_eq!(dearmor(&with_headers).unwrap(), key);
+        let with_preamble = format!("a key, below\n\n{}", TEST_KEY);
+        assert_eq!(dearmor(&with_preamble).unwrap(), key);
+    }
+
+    #[test]
+    fn dearmor_rejects_non_keys() {
+        assert!(dearmor("").is_err());
+        assert!(dearmor("not a key at all").is_err());
+        assert!(dearmor(
+            "-----BEGIN PGP PUBLIC KEY BLOCK-----\n\n-----END PGP PUBLIC KEY BLOCK-----\n"
+        )
+        .is_err());
+        assert!(dearmor(
+            "-----BEGIN PGP PUBLIC KEY BLOCK-----\n\n!!!!\n-----END PGP PUBLIC KEY BLOCK-----\n"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn trusted_signer_reads_validsig() {
+        let subkey = "1111111111111111111111111111111111111111";
+        let status = format!(
+            "[GNUPG:] NEWSIG\n\
+             [GNUPG:] GOODSIG 293ACD0907D9495A Natanael Copa <ncopa@alpinelinux.org>\n\
+             [GNUPG:] VALIDSIG {} 2023-05-09 1683622183 0 4 0 1 10 00 {}\n",
+            subkey, TEST_FINGERPRINT
+        );
+        // Signed with a subkey, but the primary key is trusted.
+        assert_eq!(
+            trusted_signer(&status, &[TEST_FINGERPRINT]),
+            Some(TEST_FINGERPRINT)
+        );
+        // Fingerprints are compared case-insensitively.
+        let lowercase = subkey.to_lowercase();
+        assert_eq!(
+            trusted_signer(&status, &[lowercase.as_str()]),
+            Some(lowercase.as_str())
+        );
+        assert_eq!(trusted_signer(&status, &[UNTRUSTED_FINGERPRINT]), None);
+        // GOODSIG alone isn't enough.
+        assert_eq!(
+            trusted_signer(
+                "[GNUPG:] GOODSIG 293ACD0907D9495A Natanael Copa\n",
+                &[TEST_FINGERPRINT]
+            ),
+            None
+        );
+        assert_eq!(trusted_signer("", &[TEST_FINGERPRINT]), None);
+    }
+
+    #[test]
+    fn verify_signature_accepts_a_good_signature() {
+        let directory = ScratchDirectory::new("gpg");
+        let keyring = write_keyring(&directory, TEST_FINGERPRINT, TEST_KEY).unwrap();
+        let result = verify_signature(
+            &testdata("signed.txt"),
+            &testdata("signed.txt.asc"),
+            &[keyring],
+            &[TEST_FINGERPRINT],
+        );
+        result.unwrap();
+    }
+
+    #[test]
+    fn verify_signature_rejects_bad_signatures() {
+        let directory = ScratchDirectory::new("gpg");
+        let keyrings = vec![
+            write_keyring(&directory, TEST_FINGERPRINT, TEST_KEY).unwrap(),
+            write_keyring(&directory, UNTRUSTED_FINGERPRINT, UNTRUSTED_KEY).unwrap(),
+        ];
+        // The file was changed after it was signed.
+        let tampered = verify_signature(
+            &testdata("tampered.txt"),
+            &testdata("signed.txt.asc"),
+            &keyrings,
+            &[TEST_FINGERPRINT],
+        );
+        // A valid signature, but from a key that isn't trusted.
+        let untrusted = verify_signature(
+            &testdata("signed.txt"),
+            &testdata("signed.txt.untrusted.asc"),
+            &keyrings,
+            &[TEST_FINGERPRINT],
+        );
+        // The signer's key isn't in any keyring.
+        let unknown = verify_signature(
+            &testdata("signed.txt"),
+            &testdata("signed.txt.asc"),
+            &keyrings[1..],
+            &[TEST_FINGERPRINT],
+        );
+        assert!(tampered.is_err());
+        assert!(untrusted.is_err());
+        assert!(unknown.is_err());
+    }
+}
@@ -0,0 +1,313 @@
+use crate::util::{append_all, AtsiError, Result, SyncResult};
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use nix::sched::{clone, CloneFlags};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{getgid, getuid, Pid, User};
+use rlimit::Resource;
+
+/// Maps root inside of a user namespace to the invoking user. Without a
+/// mapping, everything we own shows up as unowned inside of the container,
+/// which breaks ex. overlay mounts.
+///
+/// When the user has subordinate ids in `/etc/subuid` and `/etc/subgid`,
+/// those are mapped to ids 1 and up with `newuidmap` and `newgidmap`, so
+/// that things like `chown` and `apk` work. Otherwise only root exists
+/// inside of the namespace.
+///
+/// With `keep_id`, the invoking user keeps their own uid and gid inside of
+/// the namespace instead, and root gets the subordinate id that was mapped
+/// there.
+pub fn map_ids(pid: Pid, keep_id: bool) -> SyncResult<()> {
+    let user = User::from_uid(getuid())?.map(|user| user.name);
+    let uid = getuid().as_raw();
+    let gid = getgid().as_raw();
+    let uid_ranges = subordinate_ranges(Path::new("/etc/subuid"), user.as_deref(), uid);
+    let gid_ranges = subordinate_ranges(Path::new("/etc/subgid"), user.as_deref(), uid);
+
+    let proc_dir = PathBuf::from(format!("/proc/{}", pid));
+    let single = |id: u32| {
+        if keep_id {
+            format!("{0} {0} 1", id)
+        } else {
+            format!("0 {} 1", id)
+        }
+    };
+    if uid_ranges.is_empty() || !newidmap("newuidmap", pid, &mappings(uid, &uid_ranges, keep_id)) {
+        fs::write(append_all(&proc_dir, vec!["uid_map"]), single(uid))?;
+    }
+    if gid_ranges.is_empty() || !newidmap("newgidmap", pid, &mappings(gid, &gid_ranges, keep_id)) {
+        // Unprivileged processes may only write a gid map once setgroups(2)
+        // is denied.
+        fs::write(append_all(&proc_dir, vec!["setgroups"]), "deny")?;
+        fs::write(append_all(&proc_dir, vec!["gid_map"]), single(gid))?;
+    }
+    Ok(())
+}
+
+/// Lays out `(inside, outside, count)` mappings for `id` and its
+/// subordinate ranges: `id` is root inside, and subordinate ids are handed
+/// out from 1 up.
+///
+/// With `keep_id`, `id` is mapped to itself instead, and root gets the
+/// subordinate id that would've been mapped there. Every other id maps the
+/// same either way, so files keep their owners when switching.
+fn mappings(id: u32, ranges: &[(u32, u32)], keep_id: bool) -> Vec<(u32, u32, u32)> {
+    let mut mappings = vec![(0, id, 1)];
+    let mut inside: u32 = 1;
+    for (start, count) in ranges {
+        mappings.push((inside, *start, *count));
+        inside = inside.saturating_add(*count);
+    }
+    if !keep_id || id == 0 {
+        return mappings;
+    }
+
+    let mut kept = vec![(id, id, 1)];
+    for (inside, outside, count) in mappings.into_iter().skip(1) {
+        if inside <= id && id - inside < count {
+            let before = id - inside;
+            if before > 0 {
+                kept.push((inside, outside, before));
+            }
+            kept.push((0, outside + before, 1));
+            if count - before > 1 {
+                kept.push((id + 1, outside + before + 1, count - before - 1));
+            }
+        } else {
+            kept.push((inside, outside, count));
+        }
+    }
+    kept
+}
+
+/// Returns whether `id` is mapped in a `uid_map` or `gid_map` of the
+/// current process, ex. `/proc/self/uid_map`.
+pub fn is_mapped(map_file: &Path, id: u32) -> bool {
+    let map = match fs::read_to_string(map_file) {
+        Ok(map) => map,
+        Err(_) => return false,
+    };
+    map.lines().any(|line| {
+        let fields: Vec<u32> = line
+            .split_whitespace()
+            .filter_map(|field| field.parse().ok())
+            .collect();
+        matches!(fields[..], [inside, _, count] if inside <= id && id - inside < count)
+    })
+}
+
+/// Returns the `(start, count)` ranges that `file` grants to the user, who
+/// may be listed by name or by uid.
+fn subordinate_ranges(file: &Path, user: Option<&str>, uid: u32) -> Vec<(u32, u32)> {
+    let contents = match fs::read_to_string(file) {
+        Ok(contents) => contents,
+        Err(_) => return vec![],
+    };
+    let uid = uid.to_string();
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.trim().split(':');
+            let owner = fields.next()?;
+            if owner != uid && Some(owner) != user {
+                return None;
+            }
+            let start = fields.next()?.parse().ok()?;
+            let count: u32 = fields.next()?.parse().ok()?;
+            (count > 0).then_some((start, count))
+        })
+        .collect()
+}
+
+/// Writes a mapping with the setuid `newuidmap` or `newgidmap` helpers,
+/// returning whether it worked.
+fn newidmap(binary: &str, pid: Pid, mappings: &[(u32, u32, u32)]) -> bool {
+    let mut command = Command::new(binary);
+    command.arg(pid.to_string());
+    for (inside, outside, count) in mappings {
+        command.args([inside.to_string(), outside.to_string(), count.to_string()]);
+    }
+    match command.stdin(Stdio::null()).output() {
+        Ok(output) if output.status.success() => {
+            debug!("mapped {} id range(s) with {}", mappings.len(), binary);
+            true
+        }
+        Ok(output) => {
+            warn!(
+                "{} failed, falling back to a single id: {}",
+                binary,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            false
+        }
+        Err(err) => {
+            debug!("couldn't run {}: {}", binary, err);
+            false
+        }
+    }
+}
+
+/// Removes a directory that may contain files owned by subordinate ids,
+/// which only root inside of a user namespace with the same mapping can
+/// delete.
+pub fn remove_dir_all(path: &Path) -> SyncResult<()> {
+    match fs::remove_dir_all(path) {
+        Ok(_) => return Ok(()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) if err.kind() != std::io::ErrorKind::PermissionDenied => {
+            return Err(Box::new(err))
+        }
+        Err(_) => {}
+    }
+    debug!(
+        "removing {} from inside of a user namespace",
+        path.display()
+    );
+    let removed = in_user_namespace(false, || {
+        fs::remove_dir_all(path)
+            .map_err(|err| format!("couldn't remove {}: {}", path.display(), err).into())
+    })?;
+    if !removed {
+        return Err(Box::new(AtsiError::RemoveFailed(
+            path.display().to_string(),
+        )));
+    }
+    Ok(())
+}
+
+/// Runs `f` as root inside of a new user namespace that's mapped like a
+/// container's, so that it can ex. read and chown files owned by
+/// subordinate ids. Returns whether `f` succeeded; its error is logged from
+/// inside of the namespace.
+pub fn in_user_namespace<F>(keep_id: bool, mut f: F) -> SyncResult<bool>
+where
+    F: FnMut() -> Result<()>,
+{
+    let stack_size = match Resource::STACK.get() {
+        Ok((soft, _hard)) => soft as usize,
+        Err(_) => 8 * 1024 * 1024,
+    };
+    let (setup_rx, setup_tx) = nix::unistd::pipe()?;
+    let callback = || {
+        let result: Result<()> =
+            super::container::wait_for_setup(setup_rx, setup_tx).and_then(|_| f());
+        match result {
+            Ok(_) => 0,
+            Err(err) => {
+                error!("{}", err);
+                1
+            }
+        }
+    };
+
+    let mut stack_vec = vec![0u8; stack_size];
+    let pid = clone(
+        Box::new(callback),
+        stack_vec.as_mut_slice(),
+        CloneFlags::CLONE_NEWUSER,
+        Some(nix::sys::signal::Signal::SIGCHLD as i32),
+    )?;
+    nix::unistd::close(setup_rx)?;
+    let setup = map_ids(pid, keep_id);
+    if setup.is_ok() {
+        nix::unistd::write(setup_tx, &[0])?;
+    }
+    nix::unistd::close(setup_tx)?;
+    let status = waitpid(pid, None)?;
+    setup?;
+    Ok(matches!(status, WaitStatus::Exited(_pid, 0)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::scratch::ScratchDirectory;
+
+    #[test]
+    fn subordinate_ranges_by_name_or_uid() {
+        let directory = ScratchDirectory::new("idmap");
+        let file = directory.file(
+            "subuid",
+            "alice:100000:65536\nbob:165536:65536\n1000:231072:1000\n\
+             alice:300000:0\nalice:garbage:10\nalice:400000\n",
+        );
+        assert_eq!(
+            subordinate_ranges(&file, Some("alice"), 1000),
+            vec![(100000, 65536), (231072, 1000)]
+        );
+        assert_eq!(subordinate_ranges(&file, None, 1000), vec![(231072, 1000)]);
+        assert_eq!(subordinate_ranges(&file, Some("carol"), 1001), vec![]);
+        assert_eq!(
+            subordinate_ranges(Path::new("/nonexistent"), Some("alice"), 1000),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn mappings_put_subordinate_ids_after_root() {
+        assert_eq!(mappings(1000, &[], false), vec![(0, 1000, 1)]);
+        assert_eq!(
+            mappings(1000, &[(100000, 65536), (300000, 10)], false),
+            vec![(0, 1000, 1), (1, 100000, 65536), (65537, 300000, 10)]
+        );
+    }
+
+    #[test]
+    fn mappings_with_keep_id_only_swap_root() {
+        let ranges = [(100000, 65536), (300000, 10)];
+        assert_eq!(
+            mappings(1000, &ranges, true),
+            vec![
+                (1000, 1000, 1),
+                (1, 100000, 999),
+                (0, 100999, 1),
+                (1001, 101000, 64536),
+                (65537, 300000, 10),
+            ]
+        );
+        // The displaced id is in the second range.
+        assert_eq!(
+            mappings(65540, &ranges, true),
+            vec![
+                (65540, 65540, 1),
+                (1, 100000, 65536),
+                (65537, 300000, 3),
+                (0, 300003, 1),
+                (65541, 300004, 6),
+            ]
+        );
+        // At the edges of a range.
+        assert_eq!(
+            mappings(1, &[(100000, 10)], true),
+            vec![(1, 1, 1), (0, 100000, 1), (2, 100001, 9)]
+        );
+        assert_eq!(
+            mappings(10, &[(100000, 10)], true),
+            vec![(10, 10, 1), (1, 100000, 9), (0, 100009, 1)]
+        );
+        // Without a subordinate id to displace, root stays unmapped.
+        assert_eq!(
+            mappings(1000, &[(100000, 10)], true),
+            vec![(1000, 1000, 1), (1, 100000, 10)]
+        );
+        assert_eq!(mappings(0, &ranges, true), mappings(0, &ranges, false));
+    }
+
+    #[test]
+    fn is_mapped_reads_ranges() {
+        let directory = ScratchDirectory::new("idmap");
+        let file = directory.file(
+            "uid_map",
+            "         0       1000          1\n         1     100000      65536\n",
+        );
+        assert!(is_mapped(&file, 0));
+        assert!(is_mapped(&file, 1));
+        assert!(is_mapped(&file, 65536));
+        assert!(!is_mapped(&file, 65537));
+        assert!(!is_mapped(Path::new("/nonexistent"), 0));
+    }
+}
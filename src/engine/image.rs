@@ -0,0 +1,297 @@
+use crate::util::{append_all, AtsiError, SyncResult};
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::os::unix::prelude::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::container::PersistentState;
+use super::fs_driver::FsDriver;
+
+/// What an image was committed from. Containers started from an image
+/// inherit its Alpine version, architecture and environment.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ImageMetadata {
+    pub alpine_version: String,
+    pub arch: String,
+    pub mirror: String,
+    pub packages: Vec<String>,
+    pub env_vars: HashMap<String, String>,
+    /// The container that the image was committed from.
+    pub container: String,
+    /// Seconds since the Unix epoch.
+    pub created_at: u64,
+}
+
+fn images_directory() -> PathBuf {
+    let data_dir = dirs::data_dir().expect("Must have data dir to store atsi images");
+    append_all(&data_dir, vec!["@", "images"])
+}
+
+/// A pointer to the current version of the image, see
+/// `FsDriver::install_version`.
+fn image_directory(image: &str) -> PathBuf {
+    append_all(&images_directory(), vec![image])
+}
+
+pub fn rootfs_path(image: &str) -> PathBuf {
+    append_all(&image_directory(image), vec!["rootfs"])
+}
+
+fn metadata_file(image: &str) -> PathBuf {
+    append_all(&image_directory(image), vec!["image.json"])
+}
+
+fn check_name(image: &str) -> SyncResult<()> {
+    if image.is_empty() || image.contains('/') || image.starts_with('.') {
+        return Err(Box::new(AtsiError::InvalidImageName(image.to_string())));
+    }
+    Ok(())
+}
+
+pub fn load(image: &str) -> SyncResult<ImageMetadata> {
+    check_name(image)?;
+    let metadata = match fs::read_to_string(metadata_file(image)) {
+        Ok(metadata) => metadata,
+        Err(_) => return Err(Box::new(AtsiError::ImageNotFound(image.to_string()))),
+    };
+    Ok(serde_json::from_str(&metadata)?)
+}
+
+/// Every image, sorted by name.
+pub fn list() -> SyncResult<Vec<(String, ImageMetadata)>> {
+    let entries = match fs::read_dir(images_directory()) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(Box::new(err)),
+    };
+    let mut images = vec![];
+    for entry in entries {
+        let name = entry?.file_name().to_string_lossy().to_string();
+        // Skip half-committed images.
+        if !metadata_file(&name).exists() {
+            continue;
+        }
+        let metadata = load(&name)?;
+        images.push((name, metadata));
+    }
+    images.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(images)
+}
+
+/// Snapshots the filesystem of a running container into `image`, replacing
+/// it if it already exists.
+pub fn commit(state: &PersistentState, image: &str) -> SyncResult<()> {
+    check_name(image)?;
+    info!("committing container {} as {}...", state.name(), image);
+    let staging = staging_directory(image)?;
+    if let Err(err) = snapshot(state, &staging) {
+        super::idmap::remove_dir_all(&staging)?;
+        return Err(err);
+    }
+    replace(&staging, image)?;
+    info!("committed image {}", image);
+    Ok(())
+}
+
+/// Writes an image to a gzipped tarball that can be loaded elsewhere with
+/// `import`.
+pub fn export_image(image: &str, output: &Path) -> SyncResult<()> {
+    load(image)?;
+    info!("exporting image {}...", image);
+    let directory = fs::canonicalize(image_directory(image))?;
+    export(output, |partial| archive(&directory, partial, image, false))
+}
+
+/// Like `export_image`, but exports a snapshot of a running container.
+pub fn export_container(state: &PersistentState, output: &Path) -> SyncResult<()> {
+    info!("exporting container {}...", state.name());
+    export(output, |partial| {
+        let staging = staging_directory(state.name())?;
+        let result = snapshot(state, &staging)
+            .and_then(|_| archive(&staging, partial, state.name(), state.opts().keep_id));
+        super::idmap::remove_dir_all(&staging)?;
+        result
+    })
+}
+
+/// Runs `write` against a temporary file that's only moved to `output` once
+/// it succeeds.
+fn export<F>(output: &Path, write: F) -> SyncResult<()>
+where
+    F: FnOnce(&Path) -> SyncResult<()>,
+{
+    let partial_path = PathBuf::from(format!("{}.part", output.display()));
+    if let Err(err) = write(&partial_path) {
+        let _ = fs::remove_file(&partial_path);
+        return Err(err);
+    }
+    fs::rename(&partial_path, output)?;
+    info!("exported to {}", output.display());
+    Ok(())
+}
+
+/// Loads a tarball written by `export` as `image`, replacing it if it
+/// already exists.
+pub fn import(tarball: &Path, image: &str) -> SyncResult<()> {
+    check_name(image)?;
+    let staging = staging_directory(image)?;
+    let result = unpack(tarball, &staging).and_then(|_| {
+        // Anything without metadata wasn't made by `@ export`.
+        let metadata = fs::read_to_string(append_all(&staging, vec!["image.json"]))
+            .map_err(|_| AtsiError::ImageArchiveInvalid(tarball.display().to_string()))?;
+        serde_json::from_str::<ImageMetadata>(&metadata)?;
+        if !append_all(&staging, vec!["rootfs"]).is_dir() {
+            return Err(Box::new(AtsiError::ImageArchiveInvalid(
+                tarball.display().to_string(),
+            )));
+        }
+        Ok(())
+    });
+    if let Err(err) = result {
+        super::idmap::remove_dir_all(&staging)?;
+        return Err(err);
+    }
+    replace(&staging, image)?;
+    info!("imported {} as image {}", tarball.display(), image);
+    Ok(())
+}
+
+/// A private directory next to the images to build one in, so that a
+/// half-written image is never visible.
+fn staging_directory(image: &str) -> SyncResult<PathBuf> {
+    fs::create_dir_all(images_directory())?;
+    Ok(PathBuf::from(format!(
+        "{}.{}",
+        image_directory(image).display(),
+        std::process::id()
+    )))
+}
+
+/// Copies the container's filesystem and metadata into `directory`. The
+/// container's own mounts, like `/proc` or `--rw` directories, aren't part
+/// of the snapshot.
+///
+/// The copy happens inside of a user namespace that's mapped like the
+/// container's, so that files owned by its other users can be read and
+/// keep their owners.
+fn snapshot(state: &PersistentState, directory: &Path) -> SyncResult<()> {
+    // The container's root is opened out here, where we're allowed to look
+    // into its mount namespace, and then reached through the inherited fd.
+    let root_dir = File::open(format!("/proc/{}/root", state.container_pid()))?;
+    let root = PathBuf::from(format!("/proc/self/fd/{}", root_dir.as_raw_fd()));
+    let skip: Vec<PathBuf> = mount_points(*state.container_pid())?
+        .iter()
+        .map(|mount_point| append_all(&root, vec![mount_point.trim_start_matches('/')]))
+        .collect();
+    let rootfs = append_all(directory, vec!["rootfs"]);
+    let copied = super::idmap::in_user_namespace(state.opts().keep_id, || {
+        FsDriver::new()
+            .copy_dir_except(&root, &rootfs, &skip)
+            .map_err(|err| format!("couldn't copy {}: {}", state.name(), err).into())
+    })?;
+    if !copied {
+        return Err(Box::new(AtsiError::SnapshotFailed(state.name().clone())));
+    }
+
+    let metadata = ImageMetadata {
+        alpine_version: state.opts().alpine_version.clone(),
+        arch: state.opts().arch.clone(),
+        mirror: state.opts().alpine_mirror.clone(),
+        packages: state.opts().packages.clone(),
+        env_vars: state.opts().env_vars.clone(),
+        container: state.name().clone(),
+        created_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+    };
+    fs::write(
+        append_all(directory, vec!["image.json"]),
+        serde_json::to_string(&metadata)?,
+    )?;
+    Ok(())
+}
+
+/// Moves a finished image from `staging` into place as a new version.
+/// Containers that were started from the old version keep using it.
+fn replace(staging: &Path, image: &str) -> SyncResult<()> {
+    let version = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+    FsDriver::new().install_version(staging, &image_directory(image), &version.to_string())
+}
+
+/// Writes `directory`, the export of `name`, to a gzipped tarball at
+/// `output`. This happens inside of a user namespace, so that files owned
+/// by subordinate ids can be read and are archived with the owners that
+/// they have inside of a container.
+fn archive(directory: &Path, output: &Path, name: &str, keep_id: bool) -> SyncResult<()> {
+    let archived = super::idmap::in_user_namespace(keep_id, || {
+        let tarball = File::create(output)?;
+        let mut archive = tar::Builder::new(flate2::write::GzEncoder::new(
+            tarball,
+            flate2::Compression::default(),
+        ));
+        archive.follow_symlinks(false);
+        archive
+            .append_dir_all(".", directory)
+            .map_err(|err| format!("couldn't archive {}: {}", directory.display(), err))?;
+        archive.into_inner()?.finish()?;
+        Ok(())
+    })?;
+    if !archived {
+        return Err(Box::new(AtsiError::ExportFailed(name.to_string())));
+    }
+    Ok(())
+}
+
+/// Extracts a tarball written by `archive` into `directory`, inside of a
+/// user namespace so that its files get back the owners they were archived
+/// with.
+fn unpack(tarball: &Path, directory: &Path) -> SyncResult<()> {
+    let unpacked = super::idmap::in_user_namespace(false, || {
+        let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(File::open(tarball)?));
+        archive.set_preserve_permissions(true);
+        archive.set_preserve_ownerships(true);
+        archive
+            .unpack(directory)
+            .map_err(|err| format!("couldn't extract {}: {}", tarball.display(), err).into())
+    })?;
+    if !unpacked {
+        return Err(Box::new(AtsiError::ImportFailed(
+            tarball.display().to_string(),
+        )));
+    }
+    Ok(())
+}
+
+/// Returns everything mounted inside of the container, relative to its root.
+fn mount_points(pid: u32) -> SyncResult<Vec<String>> {
+    let mountinfo = fs::read_to_string(Path::new(&format!("/proc/{}/mountinfo", pid)))?;
+    // The mount point is the fifth field, ex.
+    // 36 35 98:0 /mnt1 /mnt2 rw,noatime master:1 - ext3 /dev/root rw
+    Ok(mountinfo
+        .lines()
+        .filter_map(|line| line.split(' ').nth(4))
+        .map(unescape_mount_point)
+        .filter(|mount_point| mount_point != "/")
+        .collect())
+}
+
+/// Mount points in `/proc/<pid>/mountinfo` have whitespace and backslashes
+/// escaped as octal, ex. `\040` for a space.
+fn unescape_mount_point(mount_point: &str) -> String {
+    let bytes = mount_point.as_bytes();
+    let mut unescaped = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 4 <= bytes.len() {
+            let octal = std::str::from_utf8(&bytes[i + 1..i + 4]).unwrap_or("");
+            if let Ok(byte) = u8::from_str_radix(octal, 8) {
+                unescaped.push(byte);
+                i += 4;
+                continue;
+            }
+        }
+        unescaped.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&unescaped).to_string()
+}
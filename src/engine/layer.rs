@@ -0,0 +1,199 @@
+use crate::util::{append_all, cache_dir, AtsiError, SyncResult};
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use sha2::{Digest, Sha256};
+
+use super::fs_driver::FsDriver;
+
+/// What a cached layer was built from, stored next to it so that
+/// `@ cache rebuild` can build it again.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct LayerMetadata {
+    pub alpine_version: String,
+    pub arch: String,
+    pub mirror: String,
+    pub packages: Vec<String>,
+    /// Seconds since the Unix epoch.
+    pub built_at: u64,
+}
+
+fn layers_directory() -> PathBuf {
+    append_all(&cache_dir(), vec!["layers"])
+}
+
+/// Layers are keyed by the package set, so `-P b -P a` and `-P a -P b`
+/// share the same one.
+fn normalize_packages(packages: &[String]) -> Vec<String> {
+    let mut packages = packages.to_vec();
+    packages.sort();
+    packages.dedup();
+    packages
+}
+
+/// Layers are also keyed by the rootfs they were built on, so that a new
+/// tarball for the same version gets new layers.
+pub fn layer_path(version: &str, arch: &str, packages: &[String]) -> PathBuf {
+    let base = FsDriver::new()
+        .current_version(&super::alpine::rootfs_path(version, arch))
+        .unwrap_or_default();
+    let mut hasher = Sha256::new();
+    hasher.update(format!("{}\n", base));
+    hasher.update(normalize_packages(packages).join("\n"));
+    let hash: String = hasher
+        .finalize()
+        .iter()
+        .take(8)
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    append_all(
+        &layers_directory(),
+        vec![&format!("{}-{}-{}", version, arch, hash)],
+    )
+}
+
+fn metadata_file(layer: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.json", layer.display()))
+}
+
+/// Returns the rootfs that a container with `packages` should be mounted on
+/// top of. Without any packages that's just the Alpine rootfs; otherwise
+/// it's a cached layer with the packages already installed, which is built
+/// the first time it's needed.
+pub fn ensure(
+    version: &str,
+    arch: &str,
+    mirror: &str,
+    packages: &[String],
+    apk_cache: Option<&Path>,
+    offline: bool,
+) -> SyncResult<PathBuf> {
+    let packages = normalize_packages(packages);
+    if packages.is_empty() {
+        return Ok(super::alpine::rootfs_path(version, arch));
+    }
+    let layer = layer_path(version, arch, &packages);
+    if layer.exists() {
+        debug!("using cached layer {}", layer.display());
+        return Ok(layer);
+    }
+    if offline {
+        return Err(Box::new(AtsiError::OfflineCacheMiss(
+            format!("a layer with {}", packages.join(", ")),
+            "run once without --offline to build it".to_string(),
+        )));
+    }
+    build(version, arch, mirror, &packages, apk_cache, false)
+}
+
+/// Drops every layer built on top of alpine `version`, ex. because its
+/// rootfs was replaced.
+pub fn drop_all(version: &str, arch: &str) -> SyncResult<()> {
+    for (path, metadata) in all_metadata()? {
+        if metadata.alpine_version == version && metadata.arch == arch {
+            let layer = path.with_extension("");
+            debug!("dropping layer {}", layer.display());
+            FsDriver::new().drop_version(&layer)?;
+            fs::remove_file(path)?;
+        }
+    }
+    FsDriver::new().prune_all_versions(&layers_directory())
+}
+
+fn all_metadata() -> SyncResult<Vec<(PathBuf, LayerMetadata)>> {
+    let entries = match fs::read_dir(layers_directory()) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(Box::new(err)),
+    };
+    let mut all = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+        let metadata = serde_json::from_str(&fs::read_to_string(&path)?)?;
+        all.push((path, metadata));
+    }
+    Ok(all)
+}
+
+/// Builds every cached layer again, ex. to pick up package updates.
+/// Containers that are still running keep using the old layers.
+pub fn rebuild_all() -> SyncResult<()> {
+    let fs_driver = FsDriver::new();
+    for (path, metadata) in all_metadata()? {
+        super::alpine::extract_rootfs(&metadata.alpine_version, &metadata.arch)?;
+        let layer = build(
+            &metadata.alpine_version,
+            &metadata.arch,
+            &metadata.mirror,
+            &metadata.packages,
+            Some(&super::alpine::apk_cache(
+                &metadata.alpine_version,
+                &metadata.arch,
+            )),
+            true,
+        )?;
+        // The rootfs underneath may have changed since the layer was built,
+        // which moves it to a new path.
+        let old = path.with_extension("");
+        if old != layer {
+            fs_driver.drop_version(&old)?;
+            fs::remove_file(path)?;
+        }
+    }
+    fs_driver.prune_all_versions(&layers_directory())
+}
+
+/// Builds a layer in a private directory and then moves it into place as a
+/// new version, so that a concurrent `@ run` never sees a half-built layer.
+/// With `replace`, an existing layer is swapped out for the new one.
+fn build(
+    version: &str,
+    arch: &str,
+    mirror: &str,
+    packages: &[String],
+    apk_cache: Option<&Path>,
+    replace: bool,
+) -> SyncResult<PathBuf> {
+    info!("building a layer with {}...", packages.join(", "));
+    let layer = layer_path(version, arch, packages);
+    fs::create_dir_all(layers_directory())?;
+    let staging = PathBuf::from(format!("{}.{}", layer.display(), std::process::id()));
+    let result =
+        match FsDriver::new().copy_dir(&super::alpine::rootfs_path(version, arch), &staging) {
+            Ok(_) => super::container::install_packages(
+                &staging,
+                packages,
+                super::alpine::repositories(mirror, version),
+                apk_cache,
+            ),
+            Err(err) => Err(Box::new(err).into()),
+        };
+    if let Err(err) = result {
+        super::idmap::remove_dir_all(&staging)?;
+        return Err(err);
+    }
+
+    // Losing the race to another `@ run` is fine.
+    if !replace && layer.exists() {
+        super::idmap::remove_dir_all(&staging)?;
+        return Ok(layer);
+    }
+    let built_at = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    FsDriver::new().install_version(&staging, &layer, &built_at.as_nanos().to_string())?;
+
+    let metadata = LayerMetadata {
+        alpine_version: version.to_string(),
+        arch: arch.to_string(),
+        mirror: mirror.to_string(),
+        packages: packages.to_vec(),
+        built_at: built_at.as_secs(),
+    };
+    fs::write(metadata_file(&layer), serde_json::to_string(&metadata)?)?;
+    info!("built layer {}", layer.display());
+    Ok(layer)
+}
@@ -1,15 +1,30 @@
 pub mod alpine;
+pub mod arch;
+pub mod cgroup;
 pub mod container;
 pub mod fs_driver;
+pub mod gpg;
+pub mod idmap;
+pub mod image;
+pub mod layer;
+pub mod oci;
 pub mod slirp;
+pub mod user;
 
 use tokio::time::Instant;
 
-use crate::util::{cache_dir, SyncResult};
+use crate::util::{cache_dir, AtsiError, SyncResult};
 
 use std::collections::HashMap;
-use std::fs;
-use std::path::Path;
+use std::fs::{self, File};
+use std::os::unix::prelude::{CommandExt, RawFd};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
 
 use self::fs_driver::FsDriver;
 
@@ -17,14 +32,35 @@ use self::fs_driver::FsDriver;
 pub struct RunOpts {
     pub command: String,
     pub name: String,
+    pub hostname: String,
     pub packages: Vec<String>,
     pub detach: bool,
-    pub ports: Vec<(u16, u16)>,
+    pub ports: Vec<slirp::PortForward>,
     pub immutable: bool,
     pub rw_mounts: Vec<(String, String)>,
     pub ro_mounts: Vec<(String, String)>,
     pub alpine_version: String,
+    pub alpine_mirror: String,
+    /// Whether `/etc/apk/repositories` is pointed at `alpine_mirror` when
+    /// the container starts.
+    pub set_repositories: bool,
+    pub arch: String,
+    /// The read-only rootfs that the container is mounted on top of, ex. an
+    /// Alpine rootfs or a cached package layer.
+    pub lower: PathBuf,
+    /// The shared apk cache to mount at `/etc/apk/cache`, unless opted out
+    /// of.
+    pub apk_cache: Option<PathBuf>,
+    /// Where the command starts, ex. an OCI image's `WorkingDir`.
+    pub workdir: String,
     pub env_vars: HashMap<String, String>,
+    /// `--user`, as `name|uid[:group|gid]`.
+    pub user: Option<String>,
+    /// Whether the invoking user keeps their uid and gid inside of the
+    /// container, instead of being root.
+    pub keep_id: bool,
+    pub init: bool,
+    pub limits: cgroup::Limits,
 }
 
 pub struct Engine {
@@ -46,10 +82,82 @@ impl Engine {
         Ok(())
     }
 
-    pub async fn run(&self, opts: RunOpts) -> SyncResult<()> {
-        container::ContainerEngine::new(opts)
+    /// Runs a container, returning the exit code of its command. Detached
+    /// containers always return 0 once they've been started.
+    pub async fn run(&self, opts: RunOpts) -> SyncResult<i32> {
+        if opts.detach {
+            self.run_detached(opts)?;
+            return Ok(0);
+        }
+        self.supervise(opts, None).await
+    }
+
+    /// Runs the container in the current process, staying around until it
+    /// exits so that it can be cleaned up. Once the container is set up, a
+    /// byte is written to `ready_fd`.
+    pub async fn supervise(&self, opts: RunOpts, ready_fd: Option<RawFd>) -> SyncResult<i32> {
+        if let Some(ready_fd) = ready_fd {
+            // The container's command mustn't inherit it.
+            fcntl(ready_fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+        }
+        container::ContainerEngine::new(opts, ready_fd)
             .run(self.start)
-            .await?;
+            .await
+    }
+
+    fn run_detached(&self, opts: RunOpts) -> SyncResult<()> {
+        let name = opts.name.clone();
+        self.fs.touch_dir_sync(&self.fs.container_root(&name))?;
+        let stdout = File::create(self.fs.stdout_log(&name))?;
+        let stderr = File::create(self.fs.stderr_log(&name))?;
+
+        // The supervisor reports back once the container is set up, so that
+        // ex. a port that can't be forwarded fails `@ run -d` itself.
+        let (ready_rx, ready_tx) = nix::unistd::pipe()?;
+        let mut supervisor = std::process::Command::new(std::env::current_exe()?);
+        supervisor
+            .arg("supervise")
+            .arg("--ready-fd")
+            .arg(ready_tx.to_string())
+            .arg(serde_json::to_string(&opts)?)
+            .stdin(Stdio::null())
+            .stdout(stdout)
+            .stderr(stderr);
+        unsafe {
+            supervisor.pre_exec(move || {
+                nix::unistd::close(ready_rx)?;
+                // Double-fork: the intermediate process becomes a session
+                // leader and exits immediately, so the supervisor is
+                // reparented to init and can never reacquire our terminal.
+                nix::unistd::setsid()?;
+                match nix::unistd::fork()? {
+                    nix::unistd::ForkResult::Parent { .. } => nix::libc::_exit(0),
+                    nix::unistd::ForkResult::Child => Ok(()),
+                }
+            });
+        }
+        // This only reaps the intermediate process; the supervisor itself
+        // keeps running after we exit.
+        let spawned = supervisor.spawn().and_then(|mut child| child.wait());
+        nix::unistd::close(ready_tx)?;
+        spawned?;
+        // Nothing is written if the supervisor exits before the container
+        // is set up.
+        let mut ready = [0u8; 1];
+        let read = nix::unistd::read(ready_rx, &mut ready);
+        nix::unistd::close(ready_rx)?;
+        if read? == 0 {
+            return Err(Box::new(AtsiError::ContainerStartFailed(
+                name.clone(),
+                self.fs.stderr_log(&name).display().to_string(),
+            )));
+        }
+
+        info!(
+            "started container {} in the background, logs are in {}",
+            name,
+            self.fs.container_root(&name).display()
+        );
         Ok(())
     }
 
@@ -57,17 +165,137 @@ impl Engine {
         self.fs.container_root(name).exists()
     }
 
+    fn load_state(&self, name: &str) -> SyncResult<container::PersistentState> {
+        let state = match fs::read_to_string(self.fs.persistence_file(name)) {
+            Ok(state) => state,
+            Err(_) => return Err(Box::new(AtsiError::ContainerNotFound(name.to_string()))),
+        };
+        Ok(serde_json::from_str(&state)?)
+    }
+
+    pub fn exec(&self, name: &str, command: &str) -> SyncResult<i32> {
+        let state = self.load_state(name)?;
+        if !is_alive(*state.container_pid()) {
+            return Err(Box::new(AtsiError::ContainerNotRunning(name.to_string())));
+        }
+        container::exec_in_container(&state, command)
+    }
+
+    /// Asks the container to exit with SIGTERM, and kills it if it hasn't
+    /// exited after `timeout`.
+    pub async fn stop(&self, name: &str, timeout: Duration) -> SyncResult<()> {
+        let state = self.load_state(name)?;
+        let pid = *state.container_pid();
+        if !is_alive(pid) {
+            return Err(Box::new(AtsiError::ContainerNotRunning(name.to_string())));
+        }
+
+        signal::kill(Pid::from_raw(pid as i32), Signal::SIGTERM)?;
+        if !wait_for_exit(pid, timeout).await {
+            warn!("container {} didn't stop in time, killing it", name);
+            signal::kill(Pid::from_raw(pid as i32), Signal::SIGKILL)?;
+            wait_for_exit(pid, timeout).await;
+        }
+        info!("stopped container {}", name);
+        Ok(())
+    }
+
+    pub fn kill(&self, name: &str, signal: Signal) -> SyncResult<()> {
+        let state = self.load_state(name)?;
+        if !is_alive(*state.container_pid()) {
+            return Err(Box::new(AtsiError::ContainerNotRunning(name.to_string())));
+        }
+        signal::kill(Pid::from_raw(*state.container_pid() as i32), signal)?;
+        Ok(())
+    }
+
+    /// Removes a container that's no longer running. With `force`, a
+    /// running container is killed first.
+    pub async fn rm(&self, name: &str, force: bool) -> SyncResult<()> {
+        if !self.container_exists(name) {
+            return Err(Box::new(AtsiError::ContainerNotFound(name.to_string())));
+        }
+        // Detached containers that have exited only leave their logs behind.
+        if self.fs.persistence_file(name).exists() {
+            let state = self.load_state(name)?;
+            let pid = *state.container_pid();
+            if is_alive(pid) {
+                if !force {
+                    return Err(Box::new(AtsiError::ContainerStillRunning(name.to_string())));
+                }
+                signal::kill(Pid::from_raw(pid as i32), Signal::SIGKILL)?;
+                wait_for_exit(pid, Duration::from_secs(10)).await;
+            }
+
+            #[allow(unused_must_use)]
+            {
+                // The supervisor normally cleans up after itself, so it's
+                // fine if this is already gone.
+                signal::kill(Pid::from_raw(*state.slirp_pid() as i32), Signal::SIGTERM);
+                if let Some(cgroup) = state.cgroup() {
+                    cgroup::remove(cgroup);
+                }
+            }
+        }
+        if self.container_exists(name) {
+            self.fs.remove_root(name)?;
+        }
+        info!("removed container {}", name);
+        Ok(())
+    }
+
+    /// Saves the filesystem of a running container as a named image.
+    pub fn commit(&self, name: &str, image: &str) -> SyncResult<()> {
+        let state = self.load_state(name)?;
+        if !is_alive(*state.container_pid()) {
+            return Err(Box::new(AtsiError::ContainerNotRunning(name.to_string())));
+        }
+        image::commit(&state, image)
+    }
+
+    /// Exports a running container or an image, checking for a container by
+    /// that name first.
+    pub fn export(&self, name: &str, output: &Path) -> SyncResult<()> {
+        if !self.container_exists(name) {
+            return image::export_image(name, output);
+        }
+        let state = self.load_state(name)?;
+        if !is_alive(*state.container_pid()) {
+            return Err(Box::new(AtsiError::ContainerNotRunning(name.to_string())));
+        }
+        image::export_container(&state, output)
+    }
+
+    pub fn images(&self) -> SyncResult<()> {
+        use prettytable::{row, Table};
+
+        let mut table = Table::new();
+        table.add_row(row!["NAME", "ALPINE", "ARCH", "PACKAGES"]);
+        for (name, image) in image::list()? {
+            table.add_row(row![
+                name,
+                image.alpine_version,
+                image.arch,
+                image.packages.join(", ")
+            ]);
+        }
+        table.printstd();
+        Ok(())
+    }
+
     pub async fn ps(&self, json: bool) -> SyncResult<()> {
-        use prettytable::{cell, row, Table};
+        use prettytable::{row, Table};
 
         let mut dead_containers = vec![];
         let mut live_containers = vec![];
         for container in fs::read_dir(self.fs.all_containers_root())? {
-            let state = fs::read_to_string(
-                self.fs
-                    .persistence_file(&container?.file_name().to_string_lossy().to_string()),
-            )?;
-            let state: container::PersistentState = serde_json::from_str(&state)?;
+            let name = container?.file_name().to_string_lossy().to_string();
+            // Detached containers don't have any state until they've been
+            // set up, and only leave their logs behind once they've exited.
+            if !self.fs.persistence_file(&name).exists() {
+                continue;
+            }
+            let state = self.load_state(&name)?;
             // Check if pid is still alive
             // - if alive, add to live queue
             // - if dead, add to purge queue
@@ -81,8 +309,7 @@ impl Engine {
         }
 
         for container in dead_containers {
-            let root = self.fs.container_root(container.name());
-            fs::remove_dir_all(root)?;
+            self.fs.cleanup_root(container.name())?;
             nix::sys::signal::kill(
                 nix::unistd::Pid::from_raw(*container.pid() as i32),
                 nix::sys::signal::SIGTERM,
@@ -98,12 +325,14 @@ impl Engine {
             println!("{}", serde_json::to_string(&live_containers)?);
         } else {
             let mut table = Table::new();
-            table.add_row(row!["NAME", "PID", "COMMAND"]);
+            table.add_row(row!["NAME", "PID", "ALPINE", "COMMAND", "LIMITS"]);
             for container in live_containers {
                 table.add_row(row![
                     container.name(),
                     container.pid(),
-                    container.opts().command
+                    container.opts().alpine_version,
+                    container.opts().command,
+                    container.opts().limits
                 ]);
             }
             table.printstd();
@@ -112,3 +341,19 @@ impl Engine {
         Ok(())
     }
 }
+
+fn is_alive(pid: u32) -> bool {
+    Path::new(&format!("/proc/{}", pid)).exists()
+}
+
+/// Returns whether `pid` exited within `timeout`.
+async fn wait_for_exit(pid: u32, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while is_alive(pid) {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    true
+}
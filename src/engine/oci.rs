@@ -0,0 +1,486 @@
+use crate::util::{append_all, cache_dir, AtsiError, SyncResult};
+
+use std::collections::HashMap;
+use std::fs::{self, File, Permissions};
+use std::io::{BufRead, BufReader, Read};
+use std::os::unix::prelude::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+
+use sha2::Sha256;
+
+#[derive(serde::Deserialize)]
+struct Index {
+    manifests: Vec<Descriptor>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Descriptor {
+    media_type: Option<String>,
+    digest: String,
+    platform: Option<Platform>,
+}
+
+#[derive(serde::Deserialize)]
+struct Platform {
+    architecture: String,
+    variant: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct Manifest {
+    config: Descriptor,
+    layers: Vec<Descriptor>,
+}
+
+#[derive(serde::Deserialize)]
+struct ImageConfig {
+    architecture: String,
+    variant: Option<String>,
+    config: Option<ContainerConfig>,
+}
+
+#[derive(serde::Deserialize, Default)]
+#[serde(rename_all = "PascalCase")]
+struct ContainerConfig {
+    env: Option<Vec<String>>,
+    working_dir: Option<String>,
+    cmd: Option<Vec<String>>,
+}
+
+/// An image from an OCI image layout, unpacked into a rootfs.
+pub struct OciImage {
+    pub rootfs: PathBuf,
+    pub arch: &'static str,
+    /// From `/etc/alpine-release`, if the image is Alpine-based.
+    pub alpine_version: Option<String>,
+    pub env_vars: HashMap<String, String>,
+    pub workdir: Option<String>,
+    /// `Cmd`, joined into a single shell command.
+    pub command: Option<String>,
+}
+
+fn oci_directory() -> PathBuf {
+    append_all(&cache_dir(), vec!["oci"])
+}
+
+/// Loads the image in the OCI image layout at `layout`, unpacking its
+/// layers the first time it's used. Unpacked images are cached by their
+/// manifest digest.
+pub fn load(layout: &Path) -> SyncResult<OciImage> {
+    let index: Index =
+        serde_json::from_str(&fs::read_to_string(append_all(layout, vec!["index.json"]))?)?;
+    let descriptor = find_manifest(layout, index)?;
+    let manifest: Manifest = serde_json::from_slice(&read_blob(layout, &descriptor.digest)?)?;
+    let config: ImageConfig = serde_json::from_slice(&read_blob(layout, &manifest.config.digest)?)?;
+    let arch = normalize_arch(&config.architecture, config.variant.as_deref())?;
+
+    let (_, hex) = split_digest(&descriptor.digest)?;
+    let rootfs = append_all(&oci_directory(), vec![hex]);
+    if !rootfs.exists() {
+        unpack(layout, &manifest, &rootfs)?;
+    } else {
+        debug!("using unpacked oci image {}", rootfs.display());
+    }
+
+    let alpine_version = fs::read_to_string(append_all(&rootfs, vec!["etc", "alpine-release"]))
+        .ok()
+        .map(|release| release.trim().to_string());
+    let container_config = config.config.unwrap_or_default();
+    let env_vars = container_config
+        .env
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|var| var.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+    Ok(OciImage {
+        rootfs,
+        arch,
+        alpine_version,
+        env_vars,
+        workdir: container_config.working_dir.filter(|dir| !dir.is_empty()),
+        command: container_config
+            .cmd
+            .filter(|cmd| !cmd.is_empty())
+            .map(|cmd| {
+                cmd.iter()
+                    .map(|arg| shell_quote(arg))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            }),
+    })
+}
+
+/// Picks the manifest for the host's architecture out of the index, or the
+/// only one if there's just one. Nested indexes are followed.
+fn find_manifest(layout: &Path, index: Index) -> SyncResult<Descriptor> {
+    let host = super::arch::host()?;
+    let mut manifests = index.manifests;
+    let position = if manifests.len() == 1 {
+        Some(0)
+    } else {
+        manifests.iter().position(|manifest| {
+            manifest.platform.as_ref().map(|platform| {
+                normalize_arch(&platform.architecture, platform.variant.as_deref()).ok()
+            }) == Some(Some(host))
+        })
+    };
+    let descriptor = match position {
+        Some(position) => manifests.swap_remove(position),
+        None => {
+            return Err(Box::new(AtsiError::OciLayoutInvalid(format!(
+                "no manifest for {}",
+                host
+            ))))
+        }
+    };
+    if descriptor.media_type.as_deref() == Some("application/vnd.oci.image.index.v1+json") {
+        let index: Index = serde_json::from_slice(&read_blob(layout, &descriptor.digest)?)?;
+        return find_manifest(layout, index);
+    }
+    Ok(descriptor)
+}
+
+/// Turns an OCI architecture and variant into the name Alpine uses.
+fn normalize_arch(architecture: &str, variant: Option<&str>) -> SyncResult<&'static str> {
+    match (architecture, variant) {
+        ("386", _) => Ok("x86"),
+        ("arm", Some("v6")) => Ok("armhf"),
+        ("arm", _) => Ok("armv7"),
+        (architecture, _) => super::arch::normalize(architecture),
+    }
+}
+
+fn split_digest(digest: &str) -> SyncResult<(&str, &str)> {
+    match digest.split_once(':') {
+        Some((algorithm, hex))
+            if algorithm == "sha256" && hex.chars().all(|c| c.is_ascii_hexdigit()) =>
+        {
+            Ok((algorithm, hex))
+        }
+        _ => Err(Box::new(AtsiError::OciLayoutInvalid(format!(
+            "unsupported digest {}",
+            digest
+        )))),
+    }
+}
+
+/// Returns the path to a blob, after checking that it matches its digest.
+fn blob_path(layout: &Path, digest: &str) -> SyncResult<PathBuf> {
+    let (algorithm, hex) = split_digest(digest)?;
+    let path = append_all(layout, vec!["blobs", algorithm, hex]);
+    if super::alpine::hash_file::<Sha256>(&path)? != hex.to_lowercase() {
+        return Err(Box::new(AtsiError::OciDigestMismatch(digest.to_string())));
+    }
+    Ok(path)
+}
+
+fn read_blob(layout: &Path, digest: &str) -> SyncResult<Vec<u8>> {
+    Ok(fs::read(blob_path(layout, digest)?)?)
+}
+
+/// Applies every layer in order in a private directory, and then moves the
+/// finished rootfs into place.
+fn unpack(layout: &Path, manifest: &Manifest, rootfs: &Path) -> SyncResult<()> {
+    info!("unpacking oci image...");
+    fs::create_dir_all(oci_directory())?;
+    let staging = PathBuf::from(format!("{}.{}", rootfs.display(), std::process::id()));
+    fs::create_dir_all(&staging)?;
+    let result = (|| -> SyncResult<()> {
+        // Directory permissions are applied at the very end, so that a
+        // read-only directory in one layer doesn't stop later layers from
+        // writing into it.
+        let mut directories = HashMap::new();
+        for layer in &manifest.layers {
+            debug!("applying layer {}", layer.digest);
+            let path = blob_path(layout, &layer.digest)?;
+            apply_whiteouts(&path, &staging)?;
+            apply_layer(&path, &staging, &mut directories)?;
+        }
+        fs::create_dir_all(append_all(&staging, vec!["dev"]))?;
+        fs::create_dir_all(append_all(&staging, vec!["etc"]))?;
+        super::alpine::setup_rootfs(&staging)?;
+
+        let mut directories: Vec<(PathBuf, u32)> = directories.into_iter().collect();
+        // Children before their parents.
+        directories.sort_by_key(|(path, _)| std::cmp::Reverse(path.components().count()));
+        for (path, mode) in directories {
+            // Whiteouts in later layers may have removed it again.
+            if !path.symlink_metadata().is_ok_and(|m| m.is_dir()) {
+                continue;
+            }
+            fs::set_permissions(path, Permissions::from_mode(mode))?;
+        }
+        Ok(())
+    })();
+    if let Err(err) = result {
+        fs::remove_dir_all(&staging)?;
+        return Err(err);
+    }
+    if let Err(err) = fs::rename(&staging, rootfs) {
+        fs::remove_dir_all(&staging)?;
+        // Losing the race to another `@ run` is fine.
+        if !rootfs.exists() {
+            return Err(Box::new(err));
+        }
+    }
+    info!("unpacked oci image to {}", rootfs.display());
+    Ok(())
+}
+
+/// Layers may be plain or gzipped tarballs.
+fn open_layer(path: &Path) -> SyncResult<tar::Archive<Box<dyn Read>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let magic = reader.fill_buf()?;
+    let reader: Box<dyn Read> = if magic.starts_with(&[0x1f, 0x8b]) {
+        Box::new(flate2::read::GzDecoder::new(reader))
+    } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        return Err(Box::new(AtsiError::OciLayoutInvalid(
+            "zstd-compressed layers aren't supported".to_string(),
+        )));
+    } else {
+        Box::new(reader)
+    };
+    Ok(tar::Archive::new(reader))
+}
+
+/// Deletes whatever the layer's whiteouts hide from the layers below it.
+/// This happens before anything from the layer is extracted, so that an
+/// opaque whiteout doesn't hide the layer's own files.
+fn apply_whiteouts(layer: &Path, rootfs: &Path) -> SyncResult<()> {
+    for entry in open_layer(layer)?.entries()? {
+        let entry = entry?;
+        let path = entry.path()?;
+        let name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name,
+            None => continue,
+        };
+        let parent = match path
+            .parent()
+            .and_then(|parent| contained(rootfs, parent, false))
+        {
+            Some(parent) => parent,
+            None => continue,
+        };
+        if name == ".wh..wh..opq" {
+            if let Ok(entries) = fs::read_dir(&parent) {
+                for entry in entries {
+                    remove(&entry?.path())?;
+                }
+            }
+        } else if let Some(hidden) = name.strip_prefix(".wh.") {
+            remove(&append_all(&parent, vec![hidden]))?;
+        }
+    }
+    Ok(())
+}
+
+fn apply_layer(
+    layer: &Path,
+    rootfs: &Path,
+    directories: &mut HashMap<PathBuf, u32>,
+) -> SyncResult<()> {
+    for entry in open_layer(layer)?.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_path_buf();
+        if path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with(".wh."))
+        {
+            continue;
+        }
+        let name = match path.file_name() {
+            Some(name) => name,
+            // The root directory itself.
+            None => continue,
+        };
+        let target = match path
+            .parent()
+            .and_then(|parent| contained(rootfs, parent, true))
+        {
+            Some(parent) => parent.join(name),
+            None => {
+                warn!("skipping {}, it's outside of the rootfs", path.display());
+                continue;
+            }
+        };
+
+        let entry_type = entry.header().entry_type();
+        if entry_type.is_dir() {
+            if target.symlink_metadata().is_ok_and(|m| !m.is_dir()) {
+                remove(&target)?;
+            }
+            fs::create_dir_all(&target)?;
+            directories.insert(target, entry.header().mode()?);
+            continue;
+        }
+        if entry_type.is_character_special() || entry_type.is_block_special() {
+            debug!("skipping device node {}", path.display());
+            continue;
+        }
+        // Later layers replace whatever was at the path before.
+        if target.symlink_metadata().is_ok() {
+            remove(&target)?;
+        }
+        entry.unpack_in(rootfs)?;
+    }
+    Ok(())
+}
+
+/// Resolves `path` inside of `rootfs`, following symlinks. Returns `None`
+/// if it would end up outside of it, or if it doesn't exist and `create`
+/// isn't set. With `create`, missing directories are created on the way.
+fn contained(rootfs: &Path, path: &Path, create: bool) -> Option<PathBuf> {
+    let root = rootfs.canonicalize().ok()?;
+    let mut resolved = root.clone();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => {
+                let next = resolved.join(part);
+                if next.symlink_metadata().is_err() {
+                    if !create {
+                        return None;
+                    }
+                    fs::create_dir(&next).ok()?;
+                }
+                resolved = next.canonicalize().ok()?;
+                if !resolved.starts_with(&root) {
+                    return None;
+                }
+            }
+            Component::RootDir | Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    Some(resolved)
+}
+
+fn remove(path: &Path) -> std::io::Result<()> {
+    match path.symlink_metadata() {
+        Ok(metadata) if metadata.is_dir() => {
+            // A read-only directory from an earlier layer can't be emptied.
+            fs::set_permissions(path, Permissions::from_mode(0o700))?;
+            fs::remove_dir_all(path)
+        }
+        Ok(_) => fs::remove_file(path),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Quotes an argument for `sh -c`, unless it's obviously safe as-is.
+fn shell_quote(arg: &str) -> String {
+    if !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c))
+    {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::scratch::ScratchDirectory;
+
+    /// A scratch directory with an empty `rootfs` in it.
+    fn scratch_directory() -> (ScratchDirectory, PathBuf) {
+        let directory = ScratchDirectory::new("oci");
+        let rootfs = append_all(&directory, vec!["rootfs"]);
+        fs::create_dir_all(&rootfs).unwrap();
+        (directory, rootfs)
+    }
+
+    /// Writes a layer with an empty file at each of `paths`.
+    fn write_layer(directory: &Path, paths: &[&str]) -> PathBuf {
+        let layer = append_all(directory, vec!["layer.tar"]);
+        let mut builder = tar::Builder::new(File::create(&layer).unwrap());
+        for path in paths {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(0);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, std::io::empty())
+                .unwrap();
+        }
+        builder.finish().unwrap();
+        layer
+    }
+
+    fn apply(layer: &Path, rootfs: &Path) {
+        apply_whiteouts(layer, rootfs).unwrap();
+        apply_layer(layer, rootfs, &mut HashMap::new()).unwrap();
+    }
+
+    #[test]
+    fn whiteouts_remove_hidden_files() {
+        let (directory, rootfs) = scratch_directory();
+        fs::create_dir_all(append_all(&rootfs, vec!["etc", "gone"])).unwrap();
+        fs::write(append_all(&rootfs, vec!["etc", "kept"]), "").unwrap();
+        apply(&write_layer(&directory, &["etc/.wh.gone"]), &rootfs);
+        assert!(!append_all(&rootfs, vec!["etc", "gone"]).exists());
+        assert!(append_all(&rootfs, vec!["etc", "kept"]).exists());
+        assert!(!append_all(&rootfs, vec!["etc", ".wh.gone"]).exists());
+    }
+
+    #[test]
+    fn opaque_whiteouts_keep_the_layers_own_files() {
+        let (directory, rootfs) = scratch_directory();
+        fs::create_dir_all(append_all(&rootfs, vec!["app"])).unwrap();
+        fs::write(append_all(&rootfs, vec!["app", "old"]), "").unwrap();
+        apply(
+            &write_layer(&directory, &["app/.wh..wh..opq", "app/new"]),
+            &rootfs,
+        );
+        assert!(!append_all(&rootfs, vec!["app", "old"]).exists());
+        assert!(append_all(&rootfs, vec!["app", "new"]).exists());
+    }
+
+    #[test]
+    fn whiteouts_stay_inside_of_the_rootfs() {
+        let (directory, rootfs) = scratch_directory();
+        let outside = append_all(&directory, vec!["outside"]);
+        fs::write(&outside, "").unwrap();
+        std::os::unix::fs::symlink(&directory, append_all(&rootfs, vec!["link"])).unwrap();
+        apply(&write_layer(&directory, &["link/.wh.outside"]), &rootfs);
+        assert!(outside.exists());
+    }
+
+    #[test]
+    fn contained_resolves_inside_of_the_rootfs() {
+        let (_directory, rootfs) = scratch_directory();
+        let rootfs = rootfs.canonicalize().unwrap();
+        fs::create_dir_all(append_all(&rootfs, vec!["usr", "lib"])).unwrap();
+        std::os::unix::fs::symlink("usr/lib", append_all(&rootfs, vec!["lib"])).unwrap();
+        std::os::unix::fs::symlink("/", append_all(&rootfs, vec!["root"])).unwrap();
+
+        assert_eq!(
+            contained(&rootfs, Path::new("/lib"), false),
+            Some(append_all(&rootfs, vec!["usr", "lib"]))
+        );
+        assert_eq!(contained(&rootfs, Path::new("root/etc"), false), None);
+        assert_eq!(contained(&rootfs, Path::new("../rootfs"), false), None);
+        assert_eq!(contained(&rootfs, Path::new("missing"), false), None);
+        assert_eq!(
+            contained(&rootfs, Path::new("missing/dir"), true),
+            Some(append_all(&rootfs, vec!["missing", "dir"]))
+        );
+        assert!(append_all(&rootfs, vec!["missing", "dir"]).is_dir());
+    }
+
+    #[test]
+    fn shell_quote_only_quotes_when_needed() {
+        assert_eq!(shell_quote("nginx"), "nginx");
+        assert_eq!(shell_quote("--conf=/etc/app.conf"), "--conf=/etc/app.conf");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("a b"), "'a b'");
+        assert_eq!(shell_quote("$HOME"), "'$HOME'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+    }
+}
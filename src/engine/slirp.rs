@@ -3,6 +3,7 @@ use crate::util::{append_all, cache_dir, AtsiError, SyncResult, USER_AGENT};
 use std::fs;
 use std::fs::Permissions;
 use std::io::{Read, Write};
+use std::net::Ipv4Addr;
 use std::os::unix::net::UnixStream;
 use std::os::unix::prelude::PermissionsExt;
 use std::path::{Path, PathBuf};
@@ -11,24 +12,112 @@ use std::time::Duration;
 
 use tokio::time::sleep;
 
-const URL: &str = "https://github.com/rootless-containers/slirp4netns/releases/download/v1.2.0/slirp4netns-x86_64";
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl std::fmt::Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Protocol::Tcp => write!(f, "tcp"),
+            Protocol::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+/// A port on the host that's forwarded into the container, as given to
+/// `-p [host_ip:]outer:inner[/tcp|/udp]`.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct PortForward {
+    pub host_ip: Ipv4Addr,
+    pub host_port: u16,
+    pub guest_port: u16,
+    pub protocol: Protocol,
+}
+
+impl std::str::FromStr for PortForward {
+    type Err = AtsiError;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let invalid = || AtsiError::InvalidPortForward(spec.to_string());
+        let (ports, protocol) = match spec.rsplit_once('/') {
+            Some((ports, "tcp")) => (ports, Protocol::Tcp),
+            Some((ports, "udp")) => (ports, Protocol::Udp),
+            Some(_) => return Err(invalid()),
+            None => (spec, Protocol::Tcp),
+        };
+        let parts: Vec<&str> = ports.split(':').collect();
+        let (host_ip, host_port, guest_port) = match parts[..] {
+            [host_port, guest_port] => (Ipv4Addr::LOCALHOST, host_port, guest_port),
+            [host_ip, host_port, guest_port] => (
+                host_ip.parse().map_err(|_| invalid())?,
+                host_port,
+                guest_port,
+            ),
+            _ => return Err(invalid()),
+        };
+        Ok(Self {
+            host_ip,
+            host_port: host_port.parse().map_err(|_| invalid())?,
+            guest_port: guest_port.parse().map_err(|_| invalid())?,
+            protocol,
+        })
+    }
+}
+
+impl std::fmt::Display for PortForward {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}/{}",
+            self.host_ip, self.host_port, self.guest_port, self.protocol
+        )
+    }
+}
+
+const RELEASE_URL: &str =
+    "https://github.com/rootless-containers/slirp4netns/releases/download/v1.2.0";
+
+/// slirp4netns always runs on the host, so this is keyed by the host's
+/// architecture rather than the container's.
+pub fn bin_path() -> SyncResult<PathBuf> {
+    Ok(append_all(
+        &cache_dir(),
+        vec![&format!("slirp4netns-{}", super::arch::host()?)],
+    ))
+}
 
-pub fn bin_path() -> PathBuf {
-    append_all(&cache_dir(), vec!["slirp4netns"])
+fn download_url(arch: &str) -> SyncResult<String> {
+    let release_arch = match arch {
+        "x86_64" | "aarch64" | "riscv64" | "ppc64le" | "s390x" => arch,
+        "armv7" => "armv7l",
+        _ => return Err(Box::new(AtsiError::SlirpUnavailable(arch.to_string()))),
+    };
+    Ok(format!("{}/slirp4netns-{}", RELEASE_URL, release_arch))
 }
 
-pub async fn download_slirp4netns() -> SyncResult<()> {
-    let output_path = &bin_path();
+pub async fn download_slirp4netns(offline: bool) -> SyncResult<()> {
+    let output_path = &bin_path()?;
 
     if Path::new(output_path).exists() {
         return Ok(());
     }
+    if offline {
+        return Err(Box::new(AtsiError::OfflineCacheMiss(
+            "slirp4netns".to_string(),
+            "import it with `@ slirp import <binary>`".to_string(),
+        )));
+    }
 
-    debug!("downloading slirp4netns binary from {}", URL);
+    let url = download_url(super::arch::host()?)?;
+    debug!("downloading slirp4netns binary from {}", url);
     let slirp_bytes = reqwest::Client::builder()
         .user_agent(USER_AGENT)
         .build()?
-        .get(URL)
+        .get(url)
         .send()
         .await?
         .bytes()
@@ -43,13 +132,41 @@ pub async fn download_slirp4netns() -> SyncResult<()> {
     Ok(())
 }
 
+/// Copies a local slirp4netns binary into the cache, for hosts that can't
+/// download it.
+pub fn import_slirp4netns(binary: &Path) -> SyncResult<()> {
+    let output_path = bin_path()?;
+    let partial_path = PathBuf::from(format!("{}.part", output_path.display()));
+    fs::create_dir_all(cache_dir())?;
+    fs::copy(binary, &partial_path)?;
+    fs::set_permissions(&partial_path, Permissions::from_mode(0o755))?;
+
+    // Make sure that it actually runs here, ex. that it's for the right
+    // architecture.
+    let works = std::process::Command::new(&partial_path)
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success());
+    if !works {
+        fs::remove_file(&partial_path)?;
+        return Err(Box::new(AtsiError::SlirpBinaryInvalid(
+            binary.display().to_string(),
+        )));
+    }
+    fs::rename(&partial_path, &output_path)?;
+    info!("imported slirp4netns from {}", binary.display());
+    Ok(())
+}
+
 fn slirp_socket_path(name: &str) -> String {
     format!("/tmp/slirp4netns-{}.sock", name)
 }
 
 pub async fn spawn_for_container(name: &str, pid: u32) -> SyncResult<tokio::process::Child> {
     let slirp_socket_path = slirp_socket_path(name);
-    let slirp = tokio::process::Command::new(&bin_path())
+    let slirp = tokio::process::Command::new(bin_path()?)
         .args(vec![
             "--configure",
             "--mtu=65520",
@@ -67,29 +184,50 @@ pub async fn spawn_for_container(name: &str, pid: u32) -> SyncResult<tokio::proc
     Ok(slirp)
 }
 
-pub async fn add_port_forward(name: &str, host: &u16, container: &u16) -> SyncResult<String> {
-    slirp_exec(
-        &slirp_socket_path(name),
-        format!(
-            r#"
-        {{
-            "execute": "add_hostfwd",
-            "arguments": {{
-                "proto": "tcp",
-                "host_ip": "127.0.0.1",
-                "host_port": {},
-                "guest_port": {}
-            }}
-        }}
-    "#,
-            host, container
-        )
-        .as_str(),
-    )
-    .await
+/// A reply from the slirp4netns API, which follows QMP: either
+/// `{"return": ...}` or `{"error": {"desc": ...}}`.
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SlirpResponse<T> {
+    Return(T),
+    Error(SlirpError),
 }
 
-async fn slirp_exec(slirp_socket_path: &str, command: &str) -> SyncResult<String> {
+#[derive(serde::Deserialize)]
+struct SlirpError {
+    desc: String,
+}
+
+#[derive(serde::Deserialize)]
+struct HostFwdAdded {
+    id: u32,
+}
+
+/// Forwards a port into the container, returning the id that slirp4netns
+/// knows the forward by.
+pub async fn add_port_forward(name: &str, forward: &PortForward) -> SyncResult<u32> {
+    let command = serde_json::json!({
+        "execute": "add_hostfwd",
+        "arguments": {
+            "proto": forward.protocol,
+            "host_ip": forward.host_ip.to_string(),
+            "host_port": forward.host_port,
+            "guest_port": forward.guest_port,
+        }
+    });
+    match slirp_exec::<HostFwdAdded>(&slirp_socket_path(name), &command).await? {
+        SlirpResponse::Return(added) => Ok(added.id),
+        SlirpResponse::Error(error) => Err(Box::new(AtsiError::PortForwardFailed(
+            forward.to_string(),
+            error.desc,
+        ))),
+    }
+}
+
+async fn slirp_exec<T: serde::de::DeserializeOwned>(
+    slirp_socket_path: &str,
+    command: &serde_json::Value,
+) -> SyncResult<SlirpResponse<T>> {
     debug!("connecting to: {}", slirp_socket_path);
     let mut attempts: u8 = 0;
     let mut slirp_socket;
@@ -102,11 +240,99 @@ async fn slirp_exec(slirp_socket_path: &str, command: &str) -> SyncResult<String
         if attempts > 100 {
             return Err(Box::new(AtsiError::SlirpSocketCouldntBeFound));
         }
-        sleep(Duration::from_millis(1)).await;
+        sleep(Duration::from_millis(10)).await;
     }
     debug!("slirp socket connected (attempts={})", attempts);
-    slirp_socket.write_all(command.as_bytes())?;
+    slirp_socket.write_all(command.to_string().as_bytes())?;
     let mut res = String::new();
     slirp_socket.read_to_string(&mut res)?;
-    Ok(res)
+    debug!("slirp4netns replied: {}", res.trim());
+    Ok(serde_json::from_str(&res)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(spec: &str) -> (Ipv4Addr, u16, u16, Protocol) {
+        let forward: PortForward = spec.parse().unwrap();
+        (
+            forward.host_ip,
+            forward.host_port,
+            forward.guest_port,
+            forward.protocol,
+        )
+    }
+
+    #[test]
+    fn port_forward_defaults_to_tcp_on_localhost() {
+        assert_eq!(
+            parse("8080:80"),
+            (Ipv4Addr::LOCALHOST, 8080, 80, Protocol::Tcp)
+        );
+        assert_eq!(
+            parse("8080:80/tcp"),
+            (Ipv4Addr::LOCALHOST, 8080, 80, Protocol::Tcp)
+        );
+    }
+
+    #[test]
+    fn port_forward_with_ip_and_protocol() {
+        assert_eq!(
+            parse("0.0.0.0:5353:53/udp"),
+            (Ipv4Addr::UNSPECIFIED, 5353, 53, Protocol::Udp)
+        );
+    }
+
+    #[test]
+    fn port_forward_rejects_bad_specs() {
+        for spec in [
+            "8080:80/sctp",
+            "8080:80/",
+            "localhost:8080:80",
+            "::1:8080:80",
+            "10.0.0.300:8080:80",
+            "1.2.3.4:8080:80:90",
+            "8080",
+            "8080:http",
+            "70000:80",
+            "",
+        ] {
+            assert!(spec.parse::<PortForward>().is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn slirp_response_return() {
+        let response: SlirpResponse<HostFwdAdded> =
+            serde_json::from_str(r#"{"return": {"id": 3}}"#).unwrap();
+        assert!(matches!(
+            response,
+            SlirpResponse::Return(HostFwdAdded { id: 3 })
+        ));
+    }
+
+    #[test]
+    fn slirp_response_error() {
+        let response: SlirpResponse<HostFwdAdded> = serde_json::from_str(
+            r#"{"error": {"class": "GenericError", "desc": "bad request: add_hostfwd: slirp_add_hostfwd failed"}}"#,
+        )
+        .unwrap();
+        match response {
+            SlirpResponse::Error(error) => {
+                assert_eq!(
+                    error.desc,
+                    "bad request: add_hostfwd: slirp_add_hostfwd failed"
+                )
+            }
+            SlirpResponse::Return(_) => panic!("expected an error"),
+        }
+    }
+
+    #[test]
+    fn slirp_response_rejects_garbage() {
+        assert!(serde_json::from_str::<SlirpResponse<HostFwdAdded>>(r#"{"return": {}}"#).is_err());
+        assert!(serde_json::from_str::<SlirpResponse<HostFwdAdded>>(r#"{}"#).is_err());
+        assert!(serde_json::from_str::<SlirpResponse<HostFwdAdded>>("").is_err());
+    }
 }
@@ -0,0 +1,192 @@
+use crate::util::{AtsiError, Result};
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+/// New users get the first free uid from here up, like `adduser` does.
+const FIRST_UID: u32 = 1000;
+
+/// A user inside of the container, ready to run a command as.
+pub struct ContainerUser {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub home: String,
+}
+
+/// Resolves a `--user` spec, `name|uid[:group|gid]`, against the
+/// container's `/etc/passwd` and `/etc/group`. Users that don't exist yet
+/// are created, with a home directory in `/home`. Has to be run from inside
+/// of the container.
+pub fn ensure(spec: &str) -> Result<ContainerUser> {
+    let (user, group) = match spec.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (spec, None),
+    };
+    if user.is_empty() || group == Some("") {
+        return Err(Box::new(AtsiError::InvalidUser(spec.to_string())));
+    }
+
+    let passwd = fs::read_to_string("/etc/passwd").unwrap_or_default();
+    let existing = passwd.lines().map(split_entry).find(|entry| {
+        entry.first() == Some(&user) || (user.parse::<u32>().is_ok() && entry.get(2) == Some(&user))
+    });
+    let gid = match group {
+        Some(group) => match group_id(group) {
+            Some(gid) => Some(gid),
+            // A new user's own group is created along with them.
+            None if existing.is_none() && group == user => None,
+            None => return Err(Box::new(AtsiError::InvalidUser(spec.to_string()))),
+        },
+        None => None,
+    };
+    let mut container_user = match existing {
+        Some(entry) if entry.len() >= 6 => ContainerUser {
+            name: entry[0].to_string(),
+            uid: entry[2].parse()?,
+            gid: entry[3].parse()?,
+            home: entry[5].to_string(),
+        },
+        Some(_) => return Err(Box::new(AtsiError::InvalidUser(spec.to_string()))),
+        None => create(user, gid, &passwd)?,
+    };
+    if let Some(gid) = gid {
+        container_user.gid = gid;
+    }
+    check_mapped(container_user.uid, container_user.gid)?;
+    Ok(container_user)
+}
+
+/// The gid of `group`, which is either a gid or the name of a group in the
+/// container's `/etc/group`.
+fn group_id(group: &str) -> Option<u32> {
+    if let Ok(gid) = group.parse() {
+        return Some(gid);
+    }
+    fs::read_to_string("/etc/group")
+        .unwrap_or_default()
+        .lines()
+        .map(split_entry)
+        .find(|entry| entry.first() == Some(&group))
+        .and_then(|entry| entry.get(2).and_then(|gid| gid.parse().ok()))
+}
+
+/// Without subordinate ids only one uid and gid exist inside of the
+/// container, and switching to any other fails.
+fn check_mapped(uid: u32, gid: u32) -> Result<()> {
+    if !super::idmap::is_mapped(Path::new("/proc/self/uid_map"), uid) {
+        return Err(Box::new(AtsiError::UnmappedId(format!("uid {}", uid))));
+    }
+    if !super::idmap::is_mapped(Path::new("/proc/self/gid_map"), gid) {
+        return Err(Box::new(AtsiError::UnmappedId(format!("gid {}", gid))));
+    }
+    Ok(())
+}
+
+/// Adds a user named `user`, or `user<uid>` if it's only given as a uid,
+/// along with a home directory. Their primary group is `gid`, or a group of
+/// the same name as the user without one.
+fn create(user: &str, gid: Option<u32>, passwd: &str) -> Result<ContainerUser> {
+    let entries: Vec<Vec<&str>> = passwd.lines().map(split_entry).collect();
+    let (name, uid) = match user.parse::<u32>() {
+        Ok(uid) => (format!("user{}", uid), uid),
+        Err(_) => {
+            let taken: Vec<u32> = entries
+                .iter()
+                .filter_map(|entry| entry.get(2).and_then(|uid| uid.parse().ok()))
+                .collect();
+            let uid = (FIRST_UID..).find(|uid| !taken.contains(uid)).unwrap();
+            (user.to_string(), uid)
+        }
+    };
+    if name.contains(|c: char| c == ':' || c == '/' || c.is_whitespace()) {
+        return Err(Box::new(AtsiError::InvalidUser(user.to_string())));
+    }
+    let gid = gid.unwrap_or(uid);
+    check_mapped(uid, gid)?;
+    let home = format!("/home/{}", name);
+    debug!("creating user {} ({}:{})", name, uid, gid);
+
+    let groups = fs::read_to_string("/etc/group").unwrap_or_default();
+    let (passwd_entry, group_entry) = new_entries(&name, uid, gid, &home, &groups);
+    append_line(Path::new("/etc/passwd"), &passwd_entry)?;
+    if let Some(group_entry) = group_entry {
+        append_line(Path::new("/etc/group"), &group_entry)?;
+    }
+    fs::create_dir_all(&home)?;
+    nix::unistd::chown(
+        home.as_str(),
+        Some(nix::unistd::Uid::from_raw(uid)),
+        Some(nix::unistd::Gid::from_raw(gid)),
+    )?;
+    fs::set_permissions(&home, fs::Permissions::from_mode(0o755))?;
+
+    Ok(ContainerUser {
+        name,
+        uid,
+        gid,
+        home,
+    })
+}
+
+/// The `/etc/passwd` line for a new user, and the `/etc/group` line for
+/// their primary group unless `groups` already has a group with `gid`.
+fn new_entries(
+    name: &str,
+    uid: u32,
+    gid: u32,
+    home: &str,
+    groups: &str,
+) -> (String, Option<String>) {
+    let passwd_entry = format!("{}:x:{}:{}::{}:/bin/sh", name, uid, gid, home);
+    let group_exists = groups
+        .lines()
+        .map(split_entry)
+        .any(|entry| entry.get(2) == Some(&gid.to_string().as_str()));
+    let group_entry = (!group_exists).then(|| format!("{}:x:{}:", name, gid));
+    (passwd_entry, group_entry)
+}
+
+fn split_entry(line: &str) -> Vec<&str> {
+    line.split(':').collect()
+}
+
+fn append_line(file: &Path, line: &str) -> Result<()> {
+    let mut contents = fs::read_to_string(file).unwrap_or_default();
+    if !contents.is_empty() && !contents.ends_with('\n') {
+        contents.push('\n');
+    }
+    contents.push_str(line);
+    contents.push('\n');
+    fs::write(file, contents)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_entries_use_the_given_gid() {
+        let groups = "root:x:0:root\nusers:x:100:\n";
+        assert_eq!(
+            new_entries("dev", 1000, 1000, "/home/dev", groups),
+            (
+                "dev:x:1000:1000::/home/dev:/bin/sh".to_string(),
+                Some("dev:x:1000:".to_string())
+            )
+        );
+        assert_eq!(
+            new_entries("dev", 1000, 100, "/home/dev", groups),
+            ("dev:x:1000:100::/home/dev:/bin/sh".to_string(), None)
+        );
+        assert_eq!(
+            new_entries("dev", 1000, 1001, "/home/dev", groups),
+            (
+                "dev:x:1000:1001::/home/dev:/bin/sh".to_string(),
+                Some("dev:x:1001:".to_string())
+            )
+        );
+    }
+}
@@ -5,6 +5,7 @@ mod engine;
 mod util;
 
 use std::collections::HashMap;
+use std::time::Duration;
 
 use crate::util::SyncResult;
 
@@ -32,8 +33,9 @@ async fn main() -> SyncResult<()> {
                         .takes_value(true)
                         .help("The command to run inside of the container. Defaults to `sh`.")
                         .default_value("sh")
-                        .long_help("The command to run inside of the container. This command will be pid 1\
-                            inside the container, and will have a bare-minimum environment set up.\n\
+                        .long_help("The command to run inside of the container. This command will be run by a \
+                            minimal init as pid 1 inside the container, or will be pid 1 itself with \
+                            `--no-init`, and will have a bare-minimum environment set up.\n\
                             \n\
                             The default value for the command is `sh`, to just always get a shell.\n\
                             \n\
@@ -41,7 +43,36 @@ async fn main() -> SyncResult<()> {
                             - Get a shell: `@ run`\n\
                             - Install Python 3: `@ run -P python3`")
                 )
-                // .arg(Arg::new("detach").short('d').required(false))
+                .arg(
+                    Arg::new("detach")
+                        .short('d')
+                        .long("detach")
+                        .required(false)
+                        .takes_value(false)
+                        .help("Run the container in the background. Output is logged to the container's root.")
+                        .action(clap::ArgAction::SetTrue)
+                        ,
+                )
+                .arg(
+                    Arg::new("init")
+                        .long("init")
+                        .required(false)
+                        .takes_value(false)
+                        .overrides_with("no-init")
+                        .help("Run a minimal init as pid 1 that forwards signals to the command and reaps zombies. This is the default.")
+                        .action(clap::ArgAction::SetTrue)
+                        ,
+                )
+                .arg(
+                    Arg::new("no-init")
+                        .long("no-init")
+                        .required(false)
+                        .takes_value(false)
+                        .overrides_with("init")
+                        .help("Run the command as pid 1 directly, without an init.")
+                        .action(clap::ArgAction::SetTrue)
+                        ,
+                )
                 .arg(
                     Arg::new("immutable")
                         .short('i')
@@ -65,7 +96,7 @@ async fn main() -> SyncResult<()> {
                         .short('p')
                         .action(clap::ArgAction::Append)
                         .takes_value(true)
-                        .help("Expose a port to the host. Format is outer:inner, ex. `8080:8081`.")
+                        .help("Expose a port to the host. Format is [host_ip:]outer:inner[/tcp|/udp], ex. `8080:8081` or `0.0.0.0:5353:53/udp`. Binds to 127.0.0.1 over tcp by default.")
                         ,
                 )
                 .arg(
@@ -89,7 +120,58 @@ async fn main() -> SyncResult<()> {
                         .long("alpine")
                         .takes_value(true)
                         .default_value(engine::alpine::VERSION)
-                        .help(format!("The version of Alpine Linux to use. Default is {}", engine::alpine::VERSION).as_str())
+                        .help(format!("The version of Alpine Linux to use: latest, edge, a branch like 3.18, or a release like 3.18.4. Default is {}", engine::alpine::VERSION).as_str())
+                )
+                .arg(
+                    Arg::new("image")
+                        .long("image")
+                        .takes_value(true)
+                        .conflicts_with_all(&["alpine", "arch", "package"])
+                        .help("Start from an image saved with `@ commit` instead of a fresh Alpine rootfs.")
+                )
+                .arg(
+                    Arg::new("oci")
+                        .long("oci")
+                        .takes_value(true)
+                        .conflicts_with_all(&["image", "alpine", "arch", "package"])
+                        .help("Start from an image in a local OCI image layout directory. Its Env, WorkingDir and Cmd are used, unless a command is given.")
+                )
+                .arg(
+                    Arg::new("arch")
+                        .long("arch")
+                        .takes_value(true)
+                        .help(format!("The architecture of the Alpine rootfs, one of {}. Defaults to the host's. Foreign architectures need a qemu-user binfmt handler registered with the F flag.", engine::arch::SUPPORTED.join(", ")).as_str())
+                )
+                .arg(
+                    Arg::new("mirror")
+                        .long("mirror")
+                        .action(clap::ArgAction::Append)
+                        .takes_value(true)
+                        .help(format!("An Alpine mirror to download from, ex. `https://dl-cdn.alpinelinux.org/alpine`. Can be repeated; mirrors are tried in order. Overrides ${} and the config file.", engine::alpine::MIRROR_ENV).as_str())
+                )
+                .arg(
+                    Arg::new("no-apk-cache")
+                        .long("no-apk-cache")
+                        .required(false)
+                        .takes_value(false)
+                        .help("Don't share the host's apk package cache with this container.")
+                        .action(clap::ArgAction::SetTrue)
+                )
+                .arg(
+                    Arg::new("offline")
+                        .long("offline")
+                        .required(false)
+                        .takes_value(false)
+                        .help("Never touch the network; fail if the rootfs or slirp4netns isn't cached. See `@ rootfs import` and `@ slirp import`.")
+                        .action(clap::ArgAction::SetTrue)
+                )
+                .arg(
+                    Arg::new("verify-signature")
+                        .long("verify-signature")
+                        .required(false)
+                        .takes_value(false)
+                        .help("Require the Alpine rootfs to be signed by one of Alpine's release keys. Needs gpgv.")
+                        .action(clap::ArgAction::SetTrue)
                 )
                 .arg(
                     Arg::new("env")
@@ -98,6 +180,46 @@ async fn main() -> SyncResult<()> {
                         .takes_value(true)
                         .help("Set an environment variable. Format is `VARIABLE=value`.")
                 )
+                .arg(
+                    Arg::new("user")
+                        .long("user")
+                        .short('u')
+                        .takes_value(true)
+                        .help("Run the command as this user, ex. `alice`, `1000` or `1000:1000`. Users that don't exist yet are created.")
+                )
+                .arg(
+                    Arg::new("keep-id")
+                        .long("keep-id")
+                        .required(false)
+                        .takes_value(false)
+                        .help("Keep your own uid and gid inside of the container and run the command as them, so that files written to --rw mounts are owned by you.")
+                        .action(clap::ArgAction::SetTrue)
+                )
+                .arg(
+                    Arg::new("memory")
+                        .long("memory")
+                        .short('m')
+                        .takes_value(true)
+                        .help("Limit the container's memory usage, ex. `512M` or `2G`.")
+                )
+                .arg(
+                    Arg::new("cpus")
+                        .long("cpus")
+                        .takes_value(true)
+                        .help("Limit how many CPUs the container can use, ex. `1.5`.")
+                )
+                .arg(
+                    Arg::new("pids-limit")
+                        .long("pids-limit")
+                        .takes_value(true)
+                        .help("Limit how many processes and threads the container can have.")
+                )
+                .arg(
+                    Arg::new("hostname")
+                        .long("hostname")
+                        .takes_value(true)
+                        .help("The hostname inside of the container. Defaults to the container's name, with any characters that hostnames can't have replaced by dashes.")
+                )
                 .arg(
                     Arg::new("name")
                         .long("name")
@@ -117,27 +239,228 @@ async fn main() -> SyncResult<()> {
                     .action(clap::ArgAction::SetTrue)
             )
         )
+        .subcommand(
+            Command::new("commit")
+                .about("Save the filesystem of a running container as an image, for `@ run --image`.")
+                .arg(
+                    Arg::new("name")
+                        .takes_value(true)
+                        .required(true)
+                        .help("The name of the container.")
+                )
+                .arg(
+                    Arg::new("image")
+                        .takes_value(true)
+                        .required(true)
+                        .help("The name of the image. An existing image with this name is replaced.")
+                )
+        )
+        .subcommand(
+            Command::new("export")
+                .about("Export a running container or an image as a tarball, for `@ import`.")
+                .arg(
+                    Arg::new("name")
+                        .takes_value(true)
+                        .required(true)
+                        .help("The name of the container or image. Containers are checked first.")
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .takes_value(true)
+                        .required(true)
+                        .help("The file to write to, ex. `env.tar.gz`.")
+                )
+        )
+        .subcommand(
+            Command::new("import")
+                .about("Import a tarball made by `@ export` as an image.")
+                .arg(
+                    Arg::new("tarball")
+                        .takes_value(true)
+                        .required(true)
+                        .help("The tarball to import.")
+                )
+                .arg(
+                    Arg::new("as")
+                        .long("as")
+                        .takes_value(true)
+                        .required(true)
+                        .help("The name of the image. An existing image with this name is replaced.")
+                )
+        )
+        .subcommand(Command::new("images").about("List the images saved with `@ commit`."))
+        .subcommand(
+            Command::new("exec")
+                .visible_alias("e")
+                .about("Run a command inside of an already-running container.")
+                .arg(
+                    Arg::new("name")
+                        .takes_value(true)
+                        .required(true)
+                        .help("The name of the container.")
+                )
+                .arg(
+                    Arg::new("command")
+                        .takes_value(true)
+                        .default_value("sh")
+                        .help("The command to run inside of the container. Defaults to `sh`.")
+                )
+        )
+        .subcommand(
+            Command::new("stop")
+                .about("Stop a running container, killing it if it doesn't exit in time.")
+                .arg(
+                    Arg::new("name")
+                        .takes_value(true)
+                        .required(true)
+                        .help("The name of the container.")
+                )
+                .arg(
+                    Arg::new("time")
+                        .short('t')
+                        .long("time")
+                        .takes_value(true)
+                        .default_value("10")
+                        .help("Seconds to wait for the container to exit before killing it.")
+                )
+        )
+        .subcommand(
+            Command::new("kill")
+                .about("Send a signal to a running container.")
+                .arg(
+                    Arg::new("name")
+                        .takes_value(true)
+                        .required(true)
+                        .help("The name of the container.")
+                )
+                .arg(
+                    Arg::new("signal")
+                        .short('s')
+                        .long("signal")
+                        .takes_value(true)
+                        .default_value("SIGKILL")
+                        .help("The signal to send, ex. `SIGTERM`, `HUP` or `9`.")
+                )
+        )
+        .subcommand(
+            Command::new("rm")
+                .about("Remove a container that isn't running anymore.")
+                .arg(
+                    Arg::new("name")
+                        .takes_value(true)
+                        .required(true)
+                        .help("The name of the container.")
+                )
+                .arg(
+                    Arg::new("force")
+                        .short('f')
+                        .long("force")
+                        .takes_value(false)
+                        .help("Kill the container first if it's still running.")
+                        .action(clap::ArgAction::SetTrue)
+                )
+        )
+        .subcommand(
+            Command::new("rootfs")
+                .about("Manage cached Alpine rootfs.")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("import")
+                        .about("Import a local rootfs tarball, for use with `@ run --alpine <name>`.")
+                        .arg(
+                            Arg::new("tarball")
+                                .takes_value(true)
+                                .required(true)
+                                .help("A gzipped rootfs tarball, ex. an Alpine minirootfs.")
+                        )
+                        .arg(
+                            Arg::new("as")
+                                .long("as")
+                                .takes_value(true)
+                                .required(true)
+                                .help("The name to import it as, ex. `3.18` or `my-base`.")
+                        )
+                        .arg(
+                            Arg::new("arch")
+                                .long("arch")
+                                .takes_value(true)
+                                .help("The architecture of the rootfs. Defaults to the host's.")
+                        )
+                )
+        )
+        .subcommand(
+            Command::new("cache")
+                .about("Manage cached layers and packages.")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("rebuild")
+                        .about("Rebuild every cached package layer, ex. to pick up package updates.")
+                )
+                .subcommand(
+                    Command::new("prune-apk")
+                        .about("Delete old packages from the shared apk cache.")
+                        .arg(
+                            Arg::new("days")
+                                .long("days")
+                                .takes_value(true)
+                                .default_value("30")
+                                .help("Delete packages that were downloaded more than this many days ago.")
+                        )
+                        .arg(
+                            Arg::new("all")
+                                .long("all")
+                                .takes_value(false)
+                                .help("Delete every cached package.")
+                                .action(clap::ArgAction::SetTrue)
+                        )
+                )
+        )
+        .subcommand(
+            Command::new("slirp")
+                .about("Manage the cached slirp4netns binary.")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("import")
+                        .about("Import a local slirp4netns binary instead of downloading it.")
+                        .arg(
+                            Arg::new("binary")
+                                .takes_value(true)
+                                .required(true)
+                                .help("The slirp4netns binary.")
+                        )
+                )
+        )
+        .subcommand(
+            // Internal: the detached supervisor process that `@ run -d` forks
+            // off. Not meant to be invoked by hand.
+            Command::new("supervise")
+                .hide(true)
+                .arg(
+                    Arg::new("ready-fd")
+                        .long("ready-fd")
+                        .takes_value(true)
+                        .value_parser(clap::value_parser!(i32)),
+                )
+                .arg(Arg::new("opts").takes_value(true).required(true)),
+        )
         .get_matches();
 
     match matches.subcommand_name() {
         Some("run") => {
             let matches = matches.subcommand_matches("run").unwrap();
-            let command = matches.get_one::<String>("command").unwrap();
-            let detach: bool = false; // matches.is_present("detach");
-            let packages: Vec<String> = matches
+            let mut command = matches.get_one::<String>("command").unwrap().to_string();
+            let mut workdir = "/app".to_string();
+            let detach = *matches.get_one::<bool>("detach").unwrap_or(&false);
+            let mut packages: Vec<String> = matches
                 .get_many::<String>("package")
                 .map_or(vec![], |v| v.map(|f| f.to_string()).collect());
-            let ports: Vec<(u16, u16)> = matches.get_many::<String>("port").map_or(vec![], |v| {
-                v.map(|p| {
-                    let slice: Vec<&str> = p.split(':').collect();
-                    (
-                        slice[0].parse().expect("outer port must be valid u16"),
-                        slice[1].parse().expect("inner port must be valid u16"),
-                    )
-                })
-                .collect()
-            });
+            let ports: Vec<engine::slirp::PortForward> = matches
+                .get_many::<String>("port")
+                .map_or(Ok(vec![]), |v| v.map(|p| p.parse()).collect())?;
             let immutable = *matches.get_one::<bool>("immutable").unwrap_or(&false);
+            let init = !*matches.get_one::<bool>("no-init").unwrap_or(&false);
             let rw_mounts: Vec<(String, String)> =
                 matches.get_many::<String>("rw").map_or(vec![], |v| {
                     v.map(|p| {
@@ -158,7 +481,7 @@ async fn main() -> SyncResult<()> {
                 .get_one::<String>("alpine")
                 .cloned()
                 .unwrap_or_else(|| engine::alpine::VERSION.to_string());
-            let env_vars: HashMap<String, String> =
+            let mut env_vars: HashMap<String, String> =
                 matches
                     .get_many::<String>("env")
                     .map_or(HashMap::new(), |v| {
@@ -172,38 +495,179 @@ async fn main() -> SyncResult<()> {
                         })
                         .collect()
                     });
+            let limits = engine::cgroup::Limits {
+                memory: matches
+                    .get_one::<String>("memory")
+                    .map(|m| engine::cgroup::parse_memory(m))
+                    .transpose()?,
+                cpus: matches
+                    .get_one::<String>("cpus")
+                    .map(|c| engine::cgroup::parse_cpus(c))
+                    .transpose()?,
+                pids: matches
+                    .get_one::<String>("pids-limit")
+                    .map(|p| p.parse())
+                    .transpose()?,
+            };
+            let keep_id = *matches.get_one::<bool>("keep-id").unwrap_or(&false);
+            let user = match matches.get_one::<String>("user") {
+                Some(user) => Some(user.clone()),
+                None if keep_id => Some(format!(
+                    "{}:{}",
+                    nix::unistd::getuid(),
+                    nix::unistd::getgid()
+                )),
+                None => None,
+            };
             let name = matches.get_one::<String>("name").unwrap();
+            let hostname = match matches.get_one::<String>("hostname") {
+                Some(hostname) if !util::is_valid_hostname(hostname) => {
+                    return Err(util::AtsiError::InvalidHostname(hostname.clone()).into());
+                }
+                Some(hostname) => hostname.clone(),
+                None => util::hostname_from(name),
+            };
 
             if engine.container_exists(name) {
                 error!("@ container already exists: {}", name);
                 return Ok(());
             }
 
-            engine::slirp::download_slirp4netns().await?;
+            let image = match matches.get_one::<String>("image") {
+                Some(image) => Some((image, engine::image::load(image)?)),
+                None => None,
+            };
+            let oci = match matches.get_one::<String>("oci") {
+                Some(layout) => Some(engine::oci::load(std::path::Path::new(layout))?),
+                None => None,
+            };
+            let arch = match (&image, &oci, matches.get_one::<String>("arch")) {
+                (Some((_, image)), _, _) => engine::arch::normalize(&image.arch)?,
+                (_, Some(oci), _) => oci.arch,
+                (None, None, Some(arch)) => engine::arch::normalize(arch)?,
+                (None, None, None) => engine::arch::host()?,
+            };
+            engine::arch::check_runnable(arch)?;
+
+            let offline = *matches.get_one::<bool>("offline").unwrap_or(&false);
+            engine::slirp::download_slirp4netns(offline).await?;
             debug!(
                 "cached slirp4netns at: {}",
-                engine::slirp::bin_path().display()
-            );
-            engine::alpine::download_rootfs(&alpine_version).await?;
-            debug!(
-                "cached requested alpine rootfs at: {}",
-                engine::alpine::rootfs_path(&alpine_version).display()
+                engine::slirp::bin_path()?.display()
             );
+            let no_apk_cache = *matches.get_one::<bool>("no-apk-cache").unwrap_or(&false);
+            let mirrors = matches
+                .get_many::<String>("mirror")
+                .map_or(vec![], |v| v.cloned().collect::<Vec<String>>());
+            // Images come with their own apk repositories, which are only
+            // pointed elsewhere when asked to.
+            let set_repositories = (image.is_none() && oci.is_none()) || !mirrors.is_empty();
+            let (alpine_version, alpine_mirror, lower, apk_cache) =
+                if let Some((name, image)) = image {
+                    packages = image.packages;
+                    // Variables given with `-e` win over the image's.
+                    for (key, value) in image.env_vars {
+                        env_vars.entry(key).or_insert(value);
+                    }
+                    let apk_cache = (!no_apk_cache)
+                        .then(|| engine::alpine::apk_cache(&image.alpine_version, arch));
+                    let alpine_mirror = match mirrors.first() {
+                        Some(mirror) => mirror.clone(),
+                        None => image.mirror,
+                    };
+                    (
+                        image.alpine_version,
+                        alpine_mirror,
+                        engine::image::rootfs_path(name),
+                        apk_cache,
+                    )
+                } else if let Some(oci) = oci {
+                    for (key, value) in oci.env_vars {
+                        env_vars.entry(key).or_insert(value);
+                    }
+                    if let Some(oci_workdir) = oci.workdir {
+                        workdir = oci_workdir;
+                    }
+                    if let Some(oci_command) = oci.command {
+                        if matches.value_source("command") != Some(clap::ValueSource::CommandLine) {
+                            command = oci_command;
+                        }
+                    }
+                    let alpine_mirror = engine::alpine::mirrors(&mirrors)?.remove(0);
+                    match oci.alpine_version {
+                        Some(alpine_version) => {
+                            let apk_cache = (!no_apk_cache)
+                                .then(|| engine::alpine::apk_cache(&alpine_version, arch));
+                            (alpine_version, alpine_mirror, oci.rootfs, apk_cache)
+                        }
+                        None => {
+                            warn!("this oci image isn't Alpine-based, apk won't be set up");
+                            ("unknown".to_string(), alpine_mirror, oci.rootfs, None)
+                        }
+                    }
+                } else {
+                    let verify_signature = *matches
+                        .get_one::<bool>("verify-signature")
+                        .unwrap_or(&false);
+                    let mirrors = engine::alpine::mirrors(&mirrors)?;
+                    let alpine_version =
+                        engine::alpine::resolve_version(&alpine_version, arch, &mirrors, offline)
+                            .await?;
+                    let alpine_mirror = engine::alpine::download_rootfs(
+                        &alpine_version,
+                        arch,
+                        &mirrors,
+                        verify_signature,
+                        offline,
+                    )
+                    .await?;
+                    engine::alpine::extract_rootfs(&alpine_version, arch)?;
+                    debug!(
+                        "cached requested alpine rootfs at: {}",
+                        engine::alpine::rootfs_path(&alpine_version, arch).display()
+                    );
+                    let apk_cache =
+                        (!no_apk_cache).then(|| engine::alpine::apk_cache(&alpine_version, arch));
+                    let lower = engine::layer::ensure(
+                        &alpine_version,
+                        arch,
+                        &alpine_mirror,
+                        &packages,
+                        apk_cache.as_deref(),
+                        offline,
+                    )?;
+                    (alpine_version, alpine_mirror, lower, apk_cache)
+                };
+            // Containers record the version of the cache that they're
+            // mounted on, so that it isn't removed while they're running.
+            let lower = std::fs::canonicalize(lower)?;
 
-            engine
+            let exit_code = engine
                 .run(engine::RunOpts {
-                    command: command.to_string(),
+                    command,
                     name: name.to_string(),
+                    hostname,
                     packages,
                     detach,
                     ports,
                     immutable,
                     rw_mounts,
                     ro_mounts,
-                    alpine_version: alpine_version.to_string(),
+                    alpine_version,
+                    alpine_mirror,
+                    set_repositories,
+                    arch: arch.to_string(),
+                    lower,
+                    apk_cache,
+                    workdir,
                     env_vars,
+                    user,
+                    keep_id,
+                    init,
+                    limits,
                 })
                 .await?;
+            std::process::exit(exit_code);
         }
         Some("ps") => {
             let matches = matches.subcommand_matches("ps").unwrap();
@@ -211,6 +675,106 @@ async fn main() -> SyncResult<()> {
 
             engine::Engine::new(start).ps(json).await?;
         }
+        Some("commit") => {
+            let matches = matches.subcommand_matches("commit").unwrap();
+            let name = matches.get_one::<String>("name").unwrap();
+            let image = matches.get_one::<String>("image").unwrap();
+
+            engine.commit(name, image)?;
+        }
+        Some("export") => {
+            let matches = matches.subcommand_matches("export").unwrap();
+            let name = matches.get_one::<String>("name").unwrap();
+            let output = matches.get_one::<String>("output").unwrap();
+
+            engine.export(name, std::path::Path::new(output))?;
+        }
+        Some("import") => {
+            let matches = matches.subcommand_matches("import").unwrap();
+            let tarball = matches.get_one::<String>("tarball").unwrap();
+            let image = matches.get_one::<String>("as").unwrap();
+
+            engine::image::import(std::path::Path::new(tarball), image)?;
+        }
+        Some("images") => {
+            engine.images()?;
+        }
+        Some("exec") => {
+            let matches = matches.subcommand_matches("exec").unwrap();
+            let name = matches.get_one::<String>("name").unwrap();
+            let command = matches.get_one::<String>("command").unwrap();
+
+            let exit_code = engine.exec(name, command)?;
+            std::process::exit(exit_code);
+        }
+        Some("stop") => {
+            let matches = matches.subcommand_matches("stop").unwrap();
+            let name = matches.get_one::<String>("name").unwrap();
+            let time: u64 = matches.get_one::<String>("time").unwrap().parse()?;
+
+            engine.stop(name, Duration::from_secs(time)).await?;
+        }
+        Some("kill") => {
+            let matches = matches.subcommand_matches("kill").unwrap();
+            let name = matches.get_one::<String>("name").unwrap();
+            let signal = util::parse_signal(matches.get_one::<String>("signal").unwrap())?;
+
+            engine.kill(name, signal)?;
+        }
+        Some("rm") => {
+            let matches = matches.subcommand_matches("rm").unwrap();
+            let name = matches.get_one::<String>("name").unwrap();
+            let force = *matches.get_one::<bool>("force").unwrap_or(&false);
+
+            engine.rm(name, force).await?;
+        }
+        Some("rootfs") => {
+            let matches = matches.subcommand_matches("rootfs").unwrap();
+            if let Some(matches) = matches.subcommand_matches("import") {
+                let tarball = matches.get_one::<String>("tarball").unwrap();
+                let name = matches.get_one::<String>("as").unwrap();
+                let arch = match matches.get_one::<String>("arch") {
+                    Some(arch) => engine::arch::normalize(arch)?,
+                    None => engine::arch::host()?,
+                };
+
+                engine::alpine::import_rootfs(std::path::Path::new(tarball), name, arch)?;
+            }
+        }
+        Some("cache") => {
+            let matches = matches.subcommand_matches("cache").unwrap();
+            if matches.subcommand_matches("rebuild").is_some() {
+                engine::layer::rebuild_all()?;
+            } else if let Some(matches) = matches.subcommand_matches("prune-apk") {
+                let max_age = if *matches.get_one::<bool>("all").unwrap_or(&false) {
+                    None
+                } else {
+                    let days: u64 = matches.get_one::<String>("days").unwrap().parse()?;
+                    Some(Duration::from_secs(days * 24 * 60 * 60))
+                };
+
+                let (files, bytes) = engine::alpine::prune_apk_cache(max_age)?;
+                info!("deleted {} cached package(s), {} bytes", files, bytes);
+            }
+        }
+        Some("slirp") => {
+            let matches = matches.subcommand_matches("slirp").unwrap();
+            if let Some(matches) = matches.subcommand_matches("import") {
+                let binary = matches.get_one::<String>("binary").unwrap();
+
+                engine::slirp::import_slirp4netns(std::path::Path::new(binary))?;
+            }
+        }
+        Some("supervise") => {
+            let matches = matches.subcommand_matches("supervise").unwrap();
+            let opts: engine::RunOpts =
+                serde_json::from_str(matches.get_one::<String>("opts").unwrap())?;
+
+            let ready_fd = matches.get_one::<i32>("ready-fd").copied();
+
+            let exit_code = engine.supervise(opts, ready_fd).await?;
+            std::process::exit(exit_code);
+        }
         _ => {}
     }
 
@@ -0,0 +1,61 @@
+use super::{append_all, config_dir, AtsiError, SyncResult};
+
+use std::fs;
+use std::path::PathBuf;
+
+use yaml_rust::YamlLoader;
+
+/// Settings from `config.yaml` in `config_dir()`, ex.
+///
+/// ```yaml
+/// mirrors:
+///   - https://mirror.example.com/alpine
+///   - https://dl-cdn.alpinelinux.org/alpine
+/// ```
+#[derive(Default)]
+pub struct Config {
+    /// Alpine mirrors to try, in order.
+    pub mirrors: Vec<String>,
+}
+
+pub fn config_file() -> PathBuf {
+    append_all(&config_dir(), vec!["config.yaml"])
+}
+
+impl Config {
+    /// Loads the config file, or the default config if there isn't one.
+    pub fn load() -> SyncResult<Self> {
+        let path = config_file();
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(Box::new(err)),
+        };
+        let invalid = |reason: &str| -> Box<dyn std::error::Error + Send + Sync> {
+            Box::new(AtsiError::ConfigInvalid(format!(
+                "{}: {}",
+                path.display(),
+                reason
+            )))
+        };
+
+        let docs = YamlLoader::load_from_str(&text).map_err(|err| invalid(&err.to_string()))?;
+        let mut config = Self::default();
+        let doc = match docs.first() {
+            Some(doc) => doc,
+            None => return Ok(config),
+        };
+        if !doc["mirrors"].is_badvalue() {
+            let mirrors = doc["mirrors"]
+                .as_vec()
+                .ok_or_else(|| invalid("mirrors must be a list"))?;
+            for mirror in mirrors {
+                let mirror = mirror
+                    .as_str()
+                    .ok_or_else(|| invalid("mirrors must be urls"))?;
+                config.mirrors.push(mirror.to_string());
+            }
+        }
+        Ok(config)
+    }
+}
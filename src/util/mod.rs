@@ -1,3 +1,7 @@
+pub mod config;
+#[cfg(test)]
+pub mod scratch;
+
 use std::error::Error;
 use std::path::{Path, PathBuf};
 
@@ -12,20 +16,234 @@ pub enum AtsiError {
     GenericError(Box<dyn std::error::Error + Send + Sync>),
 
     SlirpSocketCouldntBeFound,
+    SlirpUnavailable(String),
+    SlirpBinaryInvalid(String),
+    InvalidPortForward(String),
+    PortForwardFailed(String, String),
 
     AlpineManifestInvalid,
     AlpineManifestMissing,
     AlpineManifestFileMissing,
+    AlpineManifestChecksumMissing,
+    AlpineChecksumMismatch,
+    AlpineMirrorsUnavailable,
+    InvalidAlpineVersion(String),
+    InvalidRootfsName(String),
+    OfflineCacheMiss(String, String),
+
+    GpgvMissing,
+    SigningKeyInvalid,
+    SignatureInvalid(String),
+
+    ContainerNotFound(String),
+    ContainerNotRunning(String),
+    ContainerStillRunning(String),
+    ContainerSetupAborted,
+    ContainerStartFailed(String, String),
+    PackageInstallFailed(i32),
+    RemoveFailed(String),
+    SnapshotFailed(String),
+    InvalidUser(String),
+    InvalidHostname(String),
+    UnmappedId(String),
+
+    InvalidImageName(String),
+    ImageNotFound(String),
+    ImageArchiveInvalid(String),
+    ExportFailed(String),
+    ImportFailed(String),
+    OciLayoutInvalid(String),
+    OciDigestMismatch(String),
+
+    FuseOverlayFailed(std::process::ExitStatus),
+
+    InvalidLimit(String),
+    NoDelegatedCgroup,
+    CgroupControllerUnavailable(String),
+
+    ConfigInvalid(String),
+
+    UnsupportedArch(String),
+    BinfmtHandlerMissing(String, String),
+    BinfmtHandlerNotFixed(String),
 }
 
 impl std::fmt::Display for AtsiError {
-    fn fmt(&self, _: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
-        todo!()
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
+        match self {
+            AtsiError::GenericError(err) => write!(f, "{}", err),
+            AtsiError::SlirpSocketCouldntBeFound => {
+                write!(f, "couldn't connect to the slirp4netns api socket")
+            }
+            AtsiError::SlirpUnavailable(arch) => {
+                write!(f, "slirp4netns doesn't have builds for {}", arch)
+            }
+            AtsiError::SlirpBinaryInvalid(binary) => {
+                write!(f, "{} isn't a slirp4netns binary that runs here", binary)
+            }
+            AtsiError::PortForwardFailed(forward, desc) => {
+                write!(f, "couldn't forward {}: {}", forward, desc)
+            }
+            AtsiError::InvalidPortForward(spec) => write!(
+                f,
+                "invalid port forward {}, expected [host_ip:]outer:inner[/tcp|/udp]",
+                spec
+            ),
+            AtsiError::AlpineManifestInvalid => write!(f, "alpine release manifest is invalid"),
+            AtsiError::AlpineManifestMissing => {
+                write!(f, "alpine release manifest has no minirootfs")
+            }
+            AtsiError::AlpineManifestFileMissing => {
+                write!(f, "alpine release manifest has no minirootfs file")
+            }
+            AtsiError::AlpineManifestChecksumMissing => {
+                write!(
+                    f,
+                    "alpine release manifest has no checksum for the minirootfs"
+                )
+            }
+            AtsiError::AlpineChecksumMismatch => {
+                write!(f, "downloaded alpine minirootfs doesn't match its checksum")
+            }
+            AtsiError::AlpineMirrorsUnavailable => {
+                write!(f, "couldn't download alpine from any mirror")
+            }
+            AtsiError::InvalidAlpineVersion(version) => write!(
+                f,
+                "invalid alpine version {}, expected latest, edge, a branch like 3.18 or a release like 3.18.4",
+                version
+            ),
+            AtsiError::InvalidRootfsName(name) => write!(f, "invalid rootfs name {}", name),
+            AtsiError::OfflineCacheMiss(what, hint) => {
+                write!(f, "{} isn't cached and @ is offline; {}", what, hint)
+            }
+            AtsiError::GpgvMissing => write!(
+                f,
+                "gpgv is needed to verify signatures, but it isn't installed"
+            ),
+            AtsiError::SigningKeyInvalid => write!(f, "signing key isn't a valid public key"),
+            AtsiError::SignatureInvalid(file) => write!(
+                f,
+                "{} doesn't have a valid signature from a trusted key",
+                file
+            ),
+            AtsiError::ContainerNotFound(name) => write!(f, "no such container: {}", name),
+            AtsiError::ContainerNotRunning(name) => write!(f, "container isn't running: {}", name),
+            AtsiError::ContainerStillRunning(name) => write!(
+                f,
+                "container is still running, stop it first or use --force: {}",
+                name
+            ),
+            AtsiError::ContainerSetupAborted => {
+                write!(f, "container setup was aborted by the host")
+            }
+            AtsiError::ContainerStartFailed(name, log) => {
+                write!(f, "container {} couldn't be started, see {}", name, log)
+            }
+            AtsiError::PackageInstallFailed(code) => {
+                write!(f, "installing packages failed with exit code {}", code)
+            }
+            AtsiError::RemoveFailed(path) => write!(f, "couldn't remove {}", path),
+            AtsiError::SnapshotFailed(name) => write!(f, "couldn't snapshot container {}", name),
+            AtsiError::InvalidUser(user) => write!(f, "invalid user {}", user),
+            AtsiError::InvalidHostname(hostname) => write!(
+                f,
+                "invalid hostname {}, hostnames are at most 64 characters of dot-separated letters, digits and dashes",
+                hostname
+            ),
+            AtsiError::UnmappedId(id) => write!(
+                f,
+                "{} isn't mapped into the container, add a range for your user to /etc/subuid and /etc/subgid",
+                id
+            ),
+            AtsiError::InvalidImageName(image) => write!(f, "invalid image name {}", image),
+            AtsiError::ImageNotFound(image) => write!(f, "no such image: {}", image),
+            AtsiError::ImageArchiveInvalid(tarball) => {
+                write!(f, "{} isn't an image exported by @", tarball)
+            }
+            AtsiError::ExportFailed(name) => write!(f, "couldn't export {}", name),
+            AtsiError::ImportFailed(tarball) => write!(f, "couldn't import {}", tarball),
+            AtsiError::OciLayoutInvalid(reason) => write!(f, "invalid oci image layout: {}", reason),
+            AtsiError::OciDigestMismatch(digest) => {
+                write!(f, "oci blob doesn't match its digest: {}", digest)
+            }
+            AtsiError::FuseOverlayFailed(status) => {
+                write!(f, "fuse-overlayfs failed to mount: {}", status)
+            }
+            AtsiError::InvalidLimit(limit) => write!(f, "invalid resource limit: {}", limit),
+            AtsiError::NoDelegatedCgroup => write!(
+                f,
+                "resource limits need a writable, delegated cgroup v2 hierarchy, \
+                try running @ with `systemd-run --user --scope -p Delegate=yes`"
+            ),
+            AtsiError::CgroupControllerUnavailable(controller) => write!(
+                f,
+                "the {} cgroup controller isn't delegated to this user",
+                controller
+            ),
+            AtsiError::ConfigInvalid(reason) => write!(f, "invalid config file {}", reason),
+            AtsiError::UnsupportedArch(arch) => write!(f, "unsupported architecture: {}", arch),
+            AtsiError::BinfmtHandlerMissing(arch, handler) => write!(
+                f,
+                "can't run {} binaries: no qemu-user binfmt handler is enabled at {}",
+                arch, handler
+            ),
+            AtsiError::BinfmtHandlerNotFixed(handler) => write!(
+                f,
+                "{} has to be registered with the F (fix binary) flag to work in containers",
+                handler
+            ),
+        }
     }
 }
 
 impl Error for AtsiError {}
 
+/// Parses a signal given as a number, or a name with or without the `SIG`
+/// prefix, ex. `9`, `KILL` or `SIGKILL`.
+pub fn parse_signal(signal: &str) -> SyncResult<nix::sys::signal::Signal> {
+    if let Ok(number) = signal.parse::<i32>() {
+        return Ok(nix::sys::signal::Signal::try_from(number)?);
+    }
+    let name = signal.to_uppercase();
+    if name.starts_with("SIG") {
+        Ok(name.parse()?)
+    } else {
+        Ok(format!("SIG{}", name).parse()?)
+    }
+}
+
+/// Whether `hostname` fits in the kernel's 64 bytes and is made of labels
+/// that resolvers accept: 1 to 63 letters, digits and dashes each, not
+/// starting or ending with a dash.
+pub fn is_valid_hostname(hostname: &str) -> bool {
+    hostname.len() <= 64
+        && hostname.split('.').all(|label| {
+            (1..=63).contains(&label.len())
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// A valid hostname for a container named `name`: the name itself if it's
+/// valid, or else the name with the characters that hostnames can't have
+/// replaced by dashes.
+pub fn hostname_from(name: &str) -> String {
+    if is_valid_hostname(name) {
+        return name.to_string();
+    }
+    let hostname: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .take(63)
+        .collect();
+    match hostname.trim_matches('-') {
+        "" => "localhost".to_string(),
+        hostname => hostname.to_string(),
+    }
+}
+
 pub fn append_all(buf: &Path, parts: Vec<&str>) -> PathBuf {
     let mut buf = buf.to_path_buf();
     for part in parts {
@@ -39,3 +257,73 @@ pub fn cache_dir() -> PathBuf {
     path.push("@");
     path
 }
+
+pub fn config_dir() -> PathBuf {
+    let mut path = dirs::config_dir().unwrap();
+    path.push("@");
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::signal::Signal;
+
+    #[test]
+    fn parse_signal_accepts_numbers_and_names() {
+        assert_eq!(parse_signal("9").unwrap(), Signal::SIGKILL);
+        assert_eq!(parse_signal("KILL").unwrap(), Signal::SIGKILL);
+        assert_eq!(parse_signal("SIGTERM").unwrap(), Signal::SIGTERM);
+        assert_eq!(parse_signal("hup").unwrap(), Signal::SIGHUP);
+        assert_eq!(parse_signal("sigusr1").unwrap(), Signal::SIGUSR1);
+    }
+
+    #[test]
+    fn parse_signal_rejects_unknown_signals() {
+        assert!(parse_signal("0").is_err());
+        assert!(parse_signal("999").is_err());
+        assert!(parse_signal("NOPE").is_err());
+        assert!(parse_signal("").is_err());
+    }
+
+    #[test]
+    fn valid_hostnames() {
+        assert!(is_valid_hostname("web"));
+        assert!(is_valid_hostname("web-1.example.com"));
+        assert!(is_valid_hostname(&"a".repeat(63)));
+        assert!(is_valid_hostname(&format!(
+            "{}.{}",
+            "a".repeat(31),
+            "b".repeat(32)
+        )));
+    }
+
+    #[test]
+    fn invalid_hostnames() {
+        assert!(!is_valid_hostname(""));
+        assert!(!is_valid_hostname("web."));
+        assert!(!is_valid_hostname(".web"));
+        assert!(!is_valid_hostname("web..example"));
+        assert!(!is_valid_hostname("-web"));
+        assert!(!is_valid_hostname("web-.example"));
+        assert!(!is_valid_hostname("web_1"));
+        assert!(!is_valid_hostname(&"a".repeat(64)));
+        assert!(!is_valid_hostname(&format!(
+            "{}.{}",
+            "a".repeat(32),
+            "b".repeat(32)
+        )));
+    }
+
+    #[test]
+    fn hostnames_from_container_names() {
+        assert_eq!(hostname_from("web-1.example"), "web-1.example");
+        assert_eq!(hostname_from("my_env"), "my-env");
+        assert_eq!(hostname_from("_my env."), "my-env");
+        assert_eq!(hostname_from(&"a".repeat(70)), "a".repeat(63));
+        assert_eq!(hostname_from("___"), "localhost");
+        for name in ["my_env", "_my env.", "a..b", &"a_".repeat(40), "___"] {
+            assert!(is_valid_hostname(&hostname_from(name)));
+        }
+    }
+}